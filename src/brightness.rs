@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+
+/// Mapping between perceived brightness (what is published on / received from
+/// MQTT) and the linear PWM duty cycle that Tuya devices expect.
+///
+/// Both ends of the mapping use the 0.0 - 1.0 range.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BrightnessCurve {
+    /// Pass brightness through unchanged (previous behavior)
+    #[default]
+    Linear,
+
+    /// output = brightness ^ exponent, 2.2 is a good starting point
+    Gamma { exponent: f32 },
+
+    /// Treat brightness as CIE 1976 lightness (L* / 100) and convert it to
+    /// relative luminance
+    CieLightness,
+
+    /// Custom lookup table of device output values for evenly spaced
+    /// brightness steps from 0.0 to 1.0, values in between are interpolated
+    /// linearly. The table must be monotonically increasing.
+    Lookup { table: Vec<f32> },
+}
+
+impl BrightnessCurve {
+    /// Convert perceived brightness to device output
+    pub fn to_output(&self, brightness: f32) -> f32 {
+        let brightness = brightness.clamp(0.0, 1.0);
+
+        match self {
+            BrightnessCurve::Linear => brightness,
+            BrightnessCurve::Gamma { exponent } => brightness.powf(*exponent),
+            BrightnessCurve::CieLightness => {
+                let l = brightness * 100.0;
+                if l <= 8.0 {
+                    l / CIE_KAPPA
+                } else {
+                    ((l + 16.0) / 116.0).powi(3)
+                }
+            }
+            BrightnessCurve::Lookup { table } => interpolate(table, brightness),
+        }
+        .clamp(0.0, 1.0)
+    }
+
+    /// Convert device output back to perceived brightness
+    pub fn to_perceived(&self, output: f32) -> f32 {
+        let output = output.clamp(0.0, 1.0);
+
+        match self {
+            BrightnessCurve::Linear => output,
+            BrightnessCurve::Gamma { exponent } => output.powf(1.0 / exponent),
+            BrightnessCurve::CieLightness => {
+                let l = if output <= CIE_EPSILON {
+                    output * CIE_KAPPA
+                } else {
+                    116.0 * output.cbrt() - 16.0
+                };
+                l / 100.0
+            }
+            BrightnessCurve::Lookup { table } => interpolate_inverse(table, output),
+        }
+        .clamp(0.0, 1.0)
+    }
+}
+
+const CIE_KAPPA: f32 = 903.3;
+const CIE_EPSILON: f32 = 0.008856;
+
+fn interpolate(table: &[f32], x: f32) -> f32 {
+    match table {
+        [] => x,
+        [value] => *value,
+        _ => {
+            let pos = x * (table.len() - 1) as f32;
+            let i = (pos.floor() as usize).min(table.len() - 2);
+            let t = pos - i as f32;
+            table[i] + (table[i + 1] - table[i]) * t
+        }
+    }
+}
+
+fn interpolate_inverse(table: &[f32], y: f32) -> f32 {
+    match table {
+        [] => y,
+        [_] => 1.0,
+        _ => {
+            let steps = (table.len() - 1) as f32;
+
+            if y <= table[0] {
+                return 0.0;
+            }
+
+            for (i, window) in table.windows(2).enumerate() {
+                let (lo, hi) = (window[0], window[1]);
+                if y <= hi {
+                    let t = if hi > lo { (y - lo) / (hi - lo) } else { 0.0 };
+                    return (i as f32 + t) / steps;
+                }
+            }
+
+            1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip(curve: &BrightnessCurve) {
+        for i in 0..=100 {
+            let brightness = i as f32 / 100.0;
+            let output = curve.to_output(brightness);
+            let back = curve.to_perceived(output);
+            assert!(
+                (brightness - back).abs() < 0.001,
+                "{:?}: {} -> {} -> {}",
+                curve,
+                brightness,
+                output,
+                back
+            );
+        }
+    }
+
+    #[test]
+    fn test_linear_is_identity() {
+        let curve = BrightnessCurve::Linear;
+        assert_eq!(curve.to_output(0.25), 0.25);
+        assert_eq!(curve.to_perceived(0.25), 0.25);
+    }
+
+    #[test]
+    fn test_gamma() {
+        let curve = BrightnessCurve::Gamma { exponent: 2.0 };
+        assert_eq!(curve.to_output(0.5), 0.25);
+        assert_round_trip(&curve);
+    }
+
+    #[test]
+    fn test_cie_lightness() {
+        let curve = BrightnessCurve::CieLightness;
+        assert_eq!(curve.to_output(0.0), 0.0);
+        assert_eq!(curve.to_output(1.0), 1.0);
+        // L* = 50 corresponds to roughly 18% luminance
+        assert!((curve.to_output(0.5) - 0.184).abs() < 0.001);
+        assert_round_trip(&curve);
+    }
+
+    #[test]
+    fn test_lookup_table() {
+        let curve = BrightnessCurve::Lookup {
+            table: vec![0.0, 0.1, 0.4, 1.0],
+        };
+        assert!((curve.to_output(1.0 / 3.0) - 0.1).abs() < 0.001);
+        assert!((curve.to_output(0.5) - 0.25).abs() < 0.001);
+        assert_round_trip(&curve);
+    }
+
+    #[test]
+    fn test_out_of_range_values_are_clamped() {
+        let curve = BrightnessCurve::Gamma { exponent: 2.2 };
+        assert_eq!(curve.to_output(1.5), 1.0);
+        assert_eq!(curve.to_perceived(-0.5), 0.0);
+    }
+}
@@ -0,0 +1,306 @@
+//! Thin wrapper over rumqttc's MQTT v3.1.1 and v5 clients, so the rest of the
+//! bridge doesn't need to care which protocol version is in use.
+
+use anyhow::Result;
+use bytes::Bytes;
+use rumqttc::{v5, AsyncClient, EventLoop, LastWill, MqttOptions, QoS, Transport};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// MQTT protocol version used to talk to the broker
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttProtocol {
+    /// MQTT 3.1.1
+    #[default]
+    V4,
+    /// MQTT 5, enables request/response for `/set` messages
+    V5,
+}
+
+/// Where to send the response to a request, taken from the MQTT 5 response
+/// topic and correlation data properties of the request
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplyTo {
+    pub topic: String,
+    pub correlation_data: Option<Vec<u8>>,
+}
+
+/// Message received from the broker
+pub struct IncomingMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub reply_to: Option<ReplyTo>,
+}
+
+pub enum BrokerEvent {
+    Connected,
+    Message(IncomingMessage),
+    /// A disconnect requested with `BrokerClient::disconnect` has been sent,
+    /// rumqttc flushes it (and everything queued before it) first
+    Disconnected,
+    Other,
+}
+
+/// Connection options common to both protocol versions
+pub struct BrokerOptions {
+    pub client_id: String,
+    pub host: String,
+    pub port: u16,
+    pub keep_alive: Duration,
+    pub clean_session: bool,
+    pub credentials: Option<(String, String)>,
+    pub transport: Option<Transport>,
+    pub last_will: Option<(String, String, QoS)>,
+}
+
+#[derive(Clone)]
+pub enum BrokerClient {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+pub enum BrokerEventLoop {
+    V4(Box<EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+/// User property carrying the device id on MQTT 5 messages
+const DEVICE_ID_PROPERTY: &str = "device_id";
+
+impl BrokerClient {
+    pub fn new(
+        protocol: MqttProtocol,
+        options: BrokerOptions,
+        cap: usize,
+    ) -> (BrokerClient, BrokerEventLoop) {
+        match protocol {
+            MqttProtocol::V4 => {
+                let mut mqtt_options =
+                    MqttOptions::new(options.client_id, options.host, options.port);
+                mqtt_options.set_keep_alive(options.keep_alive);
+                mqtt_options.set_clean_session(options.clean_session);
+                if let Some((username, password)) = options.credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(transport) = options.transport {
+                    mqtt_options.set_transport(transport);
+                }
+                if let Some((topic, payload, qos)) = options.last_will {
+                    mqtt_options.set_last_will(LastWill::new(topic, payload, qos, true));
+                }
+
+                let (client, eventloop) = AsyncClient::new(mqtt_options, cap);
+                (
+                    BrokerClient::V4(client),
+                    BrokerEventLoop::V4(Box::new(eventloop)),
+                )
+            }
+            MqttProtocol::V5 => {
+                let mut mqtt_options =
+                    v5::MqttOptions::new(options.client_id, options.host, options.port);
+                mqtt_options.set_keep_alive(options.keep_alive);
+                mqtt_options.set_clean_start(options.clean_session);
+                if let Some((username, password)) = options.credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(transport) = options.transport {
+                    mqtt_options.set_transport(transport);
+                }
+                if let Some((topic, payload, qos)) = options.last_will {
+                    mqtt_options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                        topic,
+                        payload,
+                        v5_qos(qos),
+                        true,
+                        None,
+                    ));
+                }
+
+                let (client, eventloop) = v5::AsyncClient::new(mqtt_options, cap);
+                (
+                    BrokerClient::V5(client),
+                    BrokerEventLoop::V5(Box::new(eventloop)),
+                )
+            }
+        }
+    }
+
+    pub async fn publish(
+        &self,
+        topic: impl Into<String>,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.publish(topic, qos, retain, payload).await?,
+            BrokerClient::V5(client) => {
+                client
+                    .publish(topic, v5_qos(qos), retain, Bytes::from(payload.into()))
+                    .await?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publish a message about a device, on MQTT 5 the device id is attached
+    /// as a user property
+    pub async fn publish_for_device(
+        &self,
+        device_id: &str,
+        topic: impl Into<String>,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        match self {
+            BrokerClient::V4(_) => self.publish(topic, qos, retain, payload).await,
+            BrokerClient::V5(client) => {
+                let properties = v5::mqttbytes::v5::PublishProperties {
+                    user_properties: vec![(DEVICE_ID_PROPERTY.to_string(), device_id.to_string())],
+                    ..Default::default()
+                };
+
+                client
+                    .publish_with_properties(
+                        topic,
+                        v5_qos(qos),
+                        retain,
+                        Bytes::from(payload.into()),
+                        properties,
+                    )
+                    .await?;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Send the response to an MQTT 5 request
+    pub async fn publish_response(
+        &self,
+        device_id: &str,
+        reply_to: &ReplyTo,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        match self {
+            // Requests never carry a response topic with MQTT 3.1.1
+            BrokerClient::V4(_) => Ok(()),
+            BrokerClient::V5(client) => {
+                let properties = v5::mqttbytes::v5::PublishProperties {
+                    correlation_data: reply_to.correlation_data.clone().map(Bytes::from),
+                    user_properties: vec![(DEVICE_ID_PROPERTY.to_string(), device_id.to_string())],
+                    ..Default::default()
+                };
+
+                client
+                    .publish_with_properties(
+                        reply_to.topic.clone(),
+                        v5::mqttbytes::QoS::AtLeastOnce,
+                        false,
+                        Bytes::from(payload.into()),
+                        properties,
+                    )
+                    .await?;
+
+                Ok(())
+            }
+        }
+    }
+
+    pub fn try_publish(
+        &self,
+        topic: impl Into<String>,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.try_publish(topic, qos, retain, payload)?,
+            BrokerClient::V5(client) => {
+                client.try_publish(topic, v5_qos(qos), retain, Bytes::from(payload.into()))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn subscribe(&self, topic: impl Into<String>, qos: QoS) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.subscribe(topic, qos).await?,
+            BrokerClient::V5(client) => client.subscribe(topic, v5_qos(qos)).await?,
+        }
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, topic: impl Into<String>) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.unsubscribe(topic).await?,
+            BrokerClient::V5(client) => client.unsubscribe(topic).await?,
+        }
+
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        match self {
+            BrokerClient::V4(client) => client.disconnect().await?,
+            BrokerClient::V5(client) => client.disconnect().await?,
+        }
+
+        Ok(())
+    }
+}
+
+impl BrokerEventLoop {
+    pub async fn poll(&mut self) -> Result<BrokerEvent> {
+        let event = match self {
+            BrokerEventLoop::V4(eventloop) => match eventloop.poll().await? {
+                rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => BrokerEvent::Connected,
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) => {
+                    BrokerEvent::Message(IncomingMessage {
+                        topic: msg.topic,
+                        payload: msg.payload.to_vec(),
+                        reply_to: None,
+                    })
+                }
+                rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) => {
+                    BrokerEvent::Disconnected
+                }
+                _ => BrokerEvent::Other,
+            },
+            BrokerEventLoop::V5(eventloop) => match eventloop.poll().await? {
+                v5::Event::Incoming(v5::Incoming::ConnAck(_)) => BrokerEvent::Connected,
+                v5::Event::Incoming(v5::Incoming::Publish(msg)) => {
+                    let reply_to = msg.properties.and_then(|properties| {
+                        Some(ReplyTo {
+                            topic: properties.response_topic?,
+                            correlation_data: properties.correlation_data.map(|data| data.to_vec()),
+                        })
+                    });
+
+                    BrokerEvent::Message(IncomingMessage {
+                        topic: String::from_utf8(msg.topic.to_vec())?,
+                        payload: msg.payload.to_vec(),
+                        reply_to,
+                    })
+                }
+                v5::Event::Outgoing(rumqttc::Outgoing::Disconnect) => BrokerEvent::Disconnected,
+                _ => BrokerEvent::Other,
+            },
+        };
+
+        Ok(event)
+    }
+}
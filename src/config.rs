@@ -1,84 +1,921 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use rand::distr::{Alphanumeric, SampleString};
+use rumqttc::QoS;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use crate::{
-    mqtt::Capabilities,
-    tuya::{TuyaConfig, TuyaDeviceConfig},
+    brightness::BrightnessCurve,
+    broker::MqttProtocol,
+    discovery::DiscoveryConfig,
+    group::{group_topic, GroupConfig},
+    homie::HomieConfig,
+    mqtt::{device_topic, Capabilities, MAX_SUPPORTED_CT, MIN_SUPPORTED_CT},
+    scene::SceneConfig,
+    tinytuya,
+    tuya::{CoverConfig, DeviceType, PowerOnBehavior, TuyaConfig, TuyaDeviceConfig},
+    tuyapi::{mesparse::TuyaVersion, redact},
 };
 
 pub type DeviceId = String;
 
-#[derive(Clone, Deserialize, Debug)]
+/// Environment variable with the path of the config file
+const CONFIG_ENV: &str = "TUYA_MQTT_CONFIG";
+
+/// Prefix of environment variables overriding config values, nested keys are
+/// separated by `__`, e.g. `TUYA_MQTT__MQTT__HOST`
+const ENV_PREFIX: &str = "TUYA_MQTT";
+const ENV_SEPARATOR: &str = "__";
+
+#[derive(Clone, Deserialize)]
 pub struct MqttConfig {
     pub id: String,
     pub host: String,
     pub port: u16,
     pub topic: String,
+    /// MQTT protocol version, "v4" (3.1.1, default) or "v5"
+    #[serde(default)]
+    pub protocol: MqttProtocol,
+    pub discovery: Option<DiscoveryConfig>,
+    pub homie: Option<HomieConfig>,
+
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Read the password from a file instead, e.g. a Docker secret
+    pub password_file: Option<String>,
+
+    /// Connect using TLS, enabled by default if `ca_file` is set
+    pub tls: Option<bool>,
+    /// CA certificate (PEM) used to verify the broker, system certificates
+    /// are used if not set
+    pub ca_file: Option<String>,
+    /// Client certificate and key (PEM) for TLS client authentication
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+
+    /// QoS (0, 1 or 2) for subscriptions, defaults to 0
+    #[serde(default, deserialize_with = "deserialize_qos")]
+    pub subscribe_qos: Option<QoS>,
+    /// QoS (0, 1 or 2) for published messages, defaults to 1
+    #[serde(default, deserialize_with = "deserialize_qos")]
+    pub publish_qos: Option<QoS>,
+    /// Retain published device state, defaults to true
+    pub retain: Option<bool>,
+    /// Keep alive interval in seconds, defaults to 5
+    pub keep_alive: Option<u64>,
+    /// Start with a clean session, defaults to true. Set to false together
+    /// with `client_id = "fixed"` to have the broker keep subscriptions and
+    /// queued messages while the bridge is offline.
+    pub clean_session: Option<bool>,
+    #[serde(default)]
+    pub client_id: ClientIdStrategy,
+
+    /// Minimum change of float fields such as brightness (0.0 - 1.0) before
+    /// device state is republished, defaults to 0 (any change)
+    pub deadband: Option<f32>,
+    /// Republish device state at least this often (in seconds) even if
+    /// nothing changed, disabled by default
+    pub full_refresh_interval: Option<u64>,
+    /// Publish the expected device state right after a `/set` command instead
+    /// of waiting for the device to report it, disabled by default
+    pub optimistic: Option<bool>,
 }
 
-#[derive(Deserialize, Debug)]
+impl fmt::Debug for MqttConfig {
+    /// Redacts the password, the config is logged on startup
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("id", &self.id)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("topic", &self.topic)
+            .field("protocol", &self.protocol)
+            .field("discovery", &self.discovery)
+            .field("homie", &self.homie)
+            .field("username", &self.username)
+            .field(
+                "password",
+                &self
+                    .password
+                    .as_ref()
+                    .map(|password| redact(password.as_bytes())),
+            )
+            .field("password_file", &self.password_file)
+            .field("tls", &self.tls)
+            .field("ca_file", &self.ca_file)
+            .field("client_cert_file", &self.client_cert_file)
+            .field("client_key_file", &self.client_key_file)
+            .field("subscribe_qos", &self.subscribe_qos)
+            .field("publish_qos", &self.publish_qos)
+            .field("retain", &self.retain)
+            .field("keep_alive", &self.keep_alive)
+            .field("clean_session", &self.clean_session)
+            .field("client_id", &self.client_id)
+            .field("deadband", &self.deadband)
+            .field("full_refresh_interval", &self.full_refresh_interval)
+            .field("optimistic", &self.optimistic)
+            .finish()
+    }
+}
+
+/// How the MQTT client id is derived from `id`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIdStrategy {
+    /// `id` with a random suffix, so that multiple instances never collide
+    #[default]
+    Random,
+    /// `id` as is, required for persistent sessions
+    Fixed,
+}
+
+fn deserialize_qos<'de, D>(deserializer: D) -> Result<Option<QoS>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<u8>::deserialize(deserializer)?
+        .map(|qos| rumqttc::mqttbytes::qos(qos).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl MqttConfig {
+    /// Topic for bridge level messages, e.g. `home/lights/tuya/bridge`
+    pub fn bridge_topic(&self) -> String {
+        self.topic.replacen('+', "bridge", 1)
+    }
+
+    /// Bridge availability topic, set as the MQTT last will
+    pub fn availability_topic(&self) -> String {
+        format!("{}/availability", self.bridge_topic())
+    }
+
+    /// Any message on this topic makes every device refresh its state
+    pub fn refresh_topic(&self) -> String {
+        format!("{}/refresh", self.bridge_topic())
+    }
+
+    pub fn subscribe_qos(&self) -> QoS {
+        self.subscribe_qos.unwrap_or(QoS::AtMostOnce)
+    }
+
+    pub fn publish_qos(&self) -> QoS {
+        self.publish_qos.unwrap_or(QoS::AtLeastOnce)
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive.unwrap_or(5))
+    }
+
+    pub fn client_id(&self) -> String {
+        match self.client_id {
+            ClientIdStrategy::Random => {
+                let random_string: String = Alphanumeric.sample_string(&mut rand::rng(), 8);
+                format!("{}-{}", self.id, random_string)
+            }
+            ClientIdStrategy::Fixed => self.id.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
 pub struct DeviceConfig {
     pub name: String,
-    pub local_key: String,
+    /// Exactly one of `local_key`, `local_key_file` (e.g. a Docker secret)
+    /// and `local_key_env` (name of an environment variable) is required
+    pub local_key: Option<String>,
+    pub local_key_file: Option<String>,
+    pub local_key_env: Option<String>,
     pub ip: String,
     pub version: String,
     pub max_brightness: Option<f32>,
     pub power_on_field: Option<String>,
     pub capabilities: Option<Capabilities>,
     pub topic: Option<String>,
+    pub brightness_curve: Option<BrightnessCurve>,
+    pub device_type: Option<DeviceType>,
+    pub sensor_field: Option<String>,
+    pub cover: Option<CoverConfig>,
+    pub refresh_dps: Option<Vec<u8>>,
+    pub allowed_dps: Option<Vec<u8>>,
+    pub denied_dps: Option<Vec<u8>>,
+    pub power_on: Option<PowerOnBehavior>,
+}
+
+impl fmt::Debug for DeviceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceConfig")
+            .field("name", &self.name)
+            .field(
+                "local_key",
+                &self.local_key.as_ref().map(|key| redact(key.as_bytes())),
+            )
+            .field("local_key_file", &self.local_key_file)
+            .field("local_key_env", &self.local_key_env)
+            .field("ip", &self.ip)
+            .field("version", &self.version)
+            .field("max_brightness", &self.max_brightness)
+            .field("power_on_field", &self.power_on_field)
+            .field("capabilities", &self.capabilities)
+            .field("topic", &self.topic)
+            .field("brightness_curve", &self.brightness_curve)
+            .field("device_type", &self.device_type)
+            .field("sensor_field", &self.sensor_field)
+            .field("cover", &self.cover)
+            .field("refresh_dps", &self.refresh_dps)
+            .field("allowed_dps", &self.allowed_dps)
+            .field("denied_dps", &self.denied_dps)
+            .field("power_on", &self.power_on)
+            .finish()
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
+    /// Where the state cache and captured scenes are saved, defaults to the
+    /// working directory
+    pub data_dir: Option<String>,
+    /// Where captured scenes are saved, defaults to `<data_dir>/scenes.json`
+    pub scenes_file: Option<String>,
     pub mqtt: MqttConfig,
+    #[serde(default)]
     pub devices: HashMap<DeviceId, DeviceConfig>,
+    /// More devices, either a file with one table per device id (like the
+    /// `[devices]` section) or a directory with one file per device named
+    /// `<device id>.toml`. Relative to the config file.
+    pub devices_path: Option<String>,
+    /// `devices.json` written by tinytuya's wizard, its devices are imported
+    /// unless they are also defined in the config file or `devices_path`.
+    /// Relative to the config file.
+    pub tinytuya_devices: Option<String>,
+    #[serde(default)]
+    pub groups: HashMap<String, GroupConfig>,
+    #[serde(default)]
+    pub scenes: HashMap<String, SceneConfig>,
+    /// Log local keys and session keys instead of redacting them, only meant
+    /// for debugging the protocol
+    pub unsafe_log_secrets: Option<bool>,
 }
 
-pub fn read_config_devices() -> Result<(MqttConfig, TuyaConfig)> {
-    let builder = config::Config::builder();
+impl DeviceConfig {
+    fn local_key(&self) -> Result<String> {
+        match (&self.local_key, &self.local_key_file, &self.local_key_env) {
+            (Some(local_key), None, None) => Ok(local_key.clone()),
+            (None, Some(path), None) => Ok(std::fs::read_to_string(path)
+                .with_context(|| format!("could not read local_key_file {}", path))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string()),
+            (None, None, Some(var)) => {
+                std::env::var(var).with_context(|| format!("could not read local_key_env {}", var))
+            }
+            (None, None, None) => Err(anyhow!(
+                "one of local_key, local_key_file or local_key_env is required"
+            )),
+            _ => Err(anyhow!(
+                "only one of local_key, local_key_file and local_key_env may be set"
+            )),
+        }
+    }
+}
+
+/// Config file given with `--config <path>` or in `TUYA_MQTT_CONFIG`
+fn configured_file() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+
+    std::env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
 
-    let root = std::env::current_dir().unwrap();
-    let sample_path = root.join("Settings.toml.example");
+/// Path of the config file, watched for changes while running
+pub fn config_file() -> PathBuf {
+    configured_file().unwrap_or_else(|| std::env::current_dir().unwrap().join("Settings.toml"))
+}
 
-    let path = root.join("Settings.toml");
+fn env_overrides() -> config::Environment {
+    config::Environment::with_prefix(ENV_PREFIX).separator(ENV_SEPARATOR)
+}
+
+fn read_file<T: serde::de::DeserializeOwned>(file: &Path) -> Result<T> {
+    config::Config::builder()
+        .add_source(config::File::from(file))
+        .build()
+        .and_then(|settings| settings.try_deserialize())
+        .with_context(|| format!("Failed to read devices from {}", file.display()))
+}
 
-    if !path.exists() && std::env::var("SKIP_SAMPLE_CONFIG").is_err() {
-        println!("Settings.toml not found, generating sample configuration.");
-        println!("Set SKIP_SAMPLE_CONFIG environment variable to opt out of this behavior.");
-        std::fs::copy(sample_path, path).unwrap();
+/// Read devices from a devices file or directory, see `Config::devices_path`
+fn read_devices(path: &Path) -> Result<HashMap<DeviceId, DeviceConfig>> {
+    if !path.is_dir() {
+        return read_file(path);
     }
 
-    let builder = builder.add_source(config::File::with_name("Settings"));
+    let mut files = std::fs::read_dir(path)
+        .with_context(|| format!("Could not read devices directory {}", path.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    files.sort();
+
+    let mut devices = HashMap::new();
+    for file in files {
+        let is_config = file
+            .extension()
+            .is_some_and(|ext| ["toml", "json", "yaml", "yml"].contains(&&*ext.to_string_lossy()));
+        let Some(device_id) = file.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if !is_config || device_id.starts_with('.') {
+            continue;
+        }
+
+        devices.insert(device_id.to_string(), read_file(&file)?);
+    }
+
+    Ok(devices)
+}
+
+/// Copy the sample configuration to `Settings.toml` on first run
+///
+/// Only called on startup, a config file that is missing on reload is most
+/// likely being saved by an editor.
+pub fn create_sample_config() -> Result<()> {
+    let path = config_file();
+    if configured_file().is_some() || path.exists() || std::env::var("SKIP_SAMPLE_CONFIG").is_ok() {
+        return Ok(());
+    }
+
+    let sample_path = std::env::current_dir()?.join("Settings.toml.example");
+
+    println!("Settings.toml not found, generating sample configuration.");
+    println!("Set SKIP_SAMPLE_CONFIG environment variable to opt out of this behavior.");
+    std::fs::copy(&sample_path, &path)
+        .with_context(|| format!("Could not copy {}", sample_path.display()))?;
+
+    Ok(())
+}
+
+pub fn read_config_devices() -> Result<(MqttConfig, TuyaConfig)> {
+    let builder = config::Config::builder();
+
+    let path = config_file();
+
+    // An explicitly configured file must exist, `Settings` in the working
+    // directory may have any extension supported by the config crate
+    let builder = if configured_file().is_some() {
+        builder.add_source(config::File::from(path.as_path()))
+    } else {
+        builder.add_source(config::File::with_name("Settings"))
+    };
+
+    let builder = builder.add_source(env_overrides());
     let settings = builder.build()?;
 
-    let config: Config = settings.try_deserialize().context(
+    let mut config: Config = settings.try_deserialize().context(
         "Failed to deserialize config, compare your config file to Settings.toml.example!",
     )?;
 
+    let config_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let devices_path = config
+        .devices_path
+        .as_ref()
+        .map(|devices_path| config_dir.join(devices_path));
+    let tinytuya_devices = config
+        .tinytuya_devices
+        .as_ref()
+        .map(|tinytuya_devices| config_dir.join(tinytuya_devices));
+
+    if let Some(devices_path) = &devices_path {
+        for (device_id, device) in read_devices(devices_path)? {
+            if config.devices.insert(device_id.clone(), device).is_some() {
+                return Err(anyhow!(
+                    "Device {} is defined in both the config file and {}",
+                    device_id,
+                    devices_path.display()
+                ));
+            }
+        }
+    }
+
+    if let Some(tinytuya_devices) = &tinytuya_devices {
+        let import = tinytuya::read_devices(tinytuya_devices, &config.devices)?;
+        for warning in import.warnings {
+            eprintln!("{}: {}", tinytuya_devices.display(), warning);
+        }
+        config.devices.extend(import.devices);
+    }
+
+    // Reported together with the other config errors, the key is left empty
+    let mut errors = vec![];
+
     let devices = config
         .devices
         .into_iter()
-        .map(|(device_id, device)| {
+        .map(|(device_id, mut device)| {
+            clamp_ct(&device_id, &mut device);
+
+            let local_key = device.local_key().unwrap_or_else(|e| {
+                errors.push(format!("Device {} ({}): {:#}", device_id, device.name, e));
+                String::new()
+            });
+
             (
                 device_id.clone(),
                 TuyaDeviceConfig {
                     name: device.name,
                     id: device_id,
-                    local_key: device.local_key,
+                    local_key,
                     ip: device.ip,
                     version: device.version,
                     max_brightness: device.max_brightness,
                     power_on_field: device.power_on_field,
                     topic: device.topic,
                     capabilities: device.capabilities,
+                    brightness_curve: device.brightness_curve.unwrap_or_default(),
+                    device_type: device.device_type.unwrap_or_default(),
+                    sensor_field: device.sensor_field,
+                    cover: device.cover,
+                    refresh_dps: device.refresh_dps,
+                    allowed_dps: device.allowed_dps,
+                    denied_dps: device.denied_dps,
+                    power_on: device.power_on.unwrap_or_default(),
                 },
             )
         })
         .collect();
 
     let mqtt_config = config.mqtt;
-    let tuya_config = TuyaConfig { devices };
+    let tuya_config = TuyaConfig {
+        devices,
+        groups: config.groups,
+        scenes: config.scenes,
+        scenes_file: config.scenes_file,
+        data_dir: config.data_dir,
+        devices_path,
+        tinytuya_devices,
+        unsafe_log_secrets: config.unsafe_log_secrets.unwrap_or(false),
+    };
+
+    errors.sort();
+    errors.extend(validate(&mqtt_config, &tuya_config));
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "Invalid configuration:\n  - {}",
+            errors.join("\n  - ")
+        ));
+    }
 
     Ok((mqtt_config, tuya_config))
 }
+
+/// Clamp `capabilities.ct` to the supported range, as discovery and Homie did
+/// before ranges were validated. Ranges entirely outside it are reported by
+/// `validate`.
+fn clamp_ct(device_id: &str, device: &mut DeviceConfig) {
+    let Some(ct) = device.capabilities.as_mut().and_then(|c| c.ct.as_mut()) else {
+        return;
+    };
+
+    let clamped = ct.start.max(MIN_SUPPORTED_CT)..ct.end.min(MAX_SUPPORTED_CT);
+    if clamped != *ct && !clamped.is_empty() {
+        eprintln!(
+            "Device {} ({}): capabilities.ct {}..{} is outside the supported range, using {}..{}",
+            device_id, device.name, ct.start, ct.end, clamped.start, clamped.end
+        );
+        *ct = clamped;
+    }
+}
+
+/// Check the config for mistakes that would otherwise only show up once a
+/// device task fails, returns a description of every problem found
+fn validate(mqtt_config: &MqttConfig, tuya_config: &TuyaConfig) -> Vec<String> {
+    let mut errors = vec![];
+
+    let mut devices: Vec<_> = tuya_config.devices.values().collect();
+    devices.sort_by(|a, b| a.id.cmp(&b.id));
+
+    // Device or group that uses each topic and IP address
+    let mut topics: HashMap<String, String> = HashMap::new();
+    let mut ips: HashMap<&str, &str> = HashMap::new();
+
+    for device in devices {
+        let mut error = |message: String| {
+            errors.push(format!(
+                "Device {} ({}): {}",
+                device.id, device.name, message
+            ))
+        };
+
+        if IpAddr::from_str(&device.ip).is_err() {
+            error(format!("ip {:?} is not a valid IP address", device.ip));
+        } else if let Some(other) = ips.insert(&device.ip, &device.id) {
+            error(format!("ip {} is also used by device {}", device.ip, other));
+        }
+
+        // Same rule as the tuyapi crypter, a missing key is reported when
+        // the key is read
+        if !device.local_key.is_empty() && device.local_key.len() != 16 {
+            error(format!(
+                "local_key must be 16 characters long, not {}",
+                device.local_key.len()
+            ));
+        }
+
+        if TuyaVersion::from_str(&device.version).is_err() {
+            error(format!(
+                "version {:?} is not supported, use 3.1, 3.3 or 3.4",
+                device.version
+            ));
+        }
+
+        let topic = device_topic(&mqtt_config.topic, device);
+        if let Some(other) = topics.insert(topic.clone(), format!("device {}", device.id)) {
+            error(format!("topic {} is also used by {}", topic, other));
+        }
+
+        if let Some(max_brightness) = device.max_brightness {
+            if !(max_brightness > 0.0 && max_brightness <= 1.0) {
+                error(format!(
+                    "max_brightness must be between 0.0 and 1.0, not {}",
+                    max_brightness
+                ));
+            }
+        }
+
+        if let Some(ct) = device.capabilities.as_ref().and_then(|c| c.ct.as_ref()) {
+            if ct.is_empty() || ct.start < MIN_SUPPORTED_CT || ct.end > MAX_SUPPORTED_CT {
+                error(format!(
+                    "capabilities.ct must be a non-empty range within {}..{}, not {}..{}",
+                    MIN_SUPPORTED_CT, MAX_SUPPORTED_CT, ct.start, ct.end
+                ));
+            }
+        }
+
+        match &device.brightness_curve {
+            BrightnessCurve::Gamma { exponent } if *exponent <= 0.0 => {
+                error(format!(
+                    "brightness curve exponent must be positive, not {}",
+                    exponent
+                ));
+            }
+            BrightnessCurve::Lookup { table }
+                if table.len() < 2
+                    || table.windows(2).any(|pair| pair[0] > pair[1])
+                    || table.iter().any(|value| !(0.0..=1.0).contains(value)) =>
+            {
+                error(
+                    "brightness curve table must have at least two increasing values between 0.0 and 1.0"
+                        .to_string(),
+                );
+            }
+            _ => {}
+        }
+
+        let cover_field = device.cover.as_ref().map(|cover| cover.field.clone());
+        for (field, dp) in [
+            ("power_on_field", &device.power_on_field),
+            ("sensor_field", &device.sensor_field),
+            ("cover.field", &cover_field),
+        ] {
+            if let Some(dp) = dp {
+                if dp.parse::<u8>().is_err() {
+                    error(format!("{} {:?} is not a DP number", field, dp));
+                }
+            }
+        }
+
+        if device.device_type == DeviceType::Sensor && device.sensor_field.is_none() {
+            error("sensors need a sensor_field".to_string());
+        }
+
+        for (field, dps) in [
+            ("refresh_dps", &device.refresh_dps),
+            ("allowed_dps", &device.allowed_dps),
+            ("denied_dps", &device.denied_dps),
+        ] {
+            if dps.as_ref().is_some_and(|dps| dps.contains(&0)) {
+                error(format!("{} contains DP 0, DPs start at 1", field));
+            }
+        }
+
+        if let (Some(allowed), Some(denied)) = (&device.allowed_dps, &device.denied_dps) {
+            if let Some(dp) = allowed.iter().find(|dp| denied.contains(dp)) {
+                error(format!("DP {} is both allowed and denied", dp));
+            }
+        }
+    }
+
+    let mut groups: Vec<_> = tuya_config.groups.iter().collect();
+    groups.sort_by_key(|(group_id, _)| *group_id);
+
+    for (group_id, group) in groups {
+        let topic = group_topic(&mqtt_config.topic, group_id, group);
+        if let Some(other) = topics.insert(topic.clone(), format!("group {}", group_id)) {
+            errors.push(format!(
+                "Group {}: topic {} is also used by {}",
+                group_id, topic, other
+            ));
+        }
+    }
+
+    errors.extend(unknown_devices(
+        &tuya_config.groups,
+        &tuya_config.scenes,
+        &tuya_config.devices,
+    ));
+
+    errors
+}
+
+/// Group members and scene devices that aren't in `devices`. Groups and scenes
+/// are only read at startup, so devices that are removed on reload are checked
+/// against them as well.
+pub fn unknown_devices(
+    groups: &HashMap<String, GroupConfig>,
+    scenes: &HashMap<String, SceneConfig>,
+    devices: &HashMap<String, TuyaDeviceConfig>,
+) -> Vec<String> {
+    let mut errors = vec![];
+
+    let mut groups: Vec<_> = groups.iter().collect();
+    groups.sort_by_key(|(group_id, _)| *group_id);
+
+    for (group_id, group) in groups {
+        for member in &group.members {
+            if !devices.contains_key(member) {
+                errors.push(format!(
+                    "Group {} contains unknown device {}",
+                    group_id, member
+                ));
+            }
+        }
+    }
+
+    let mut scenes: Vec<_> = scenes.iter().collect();
+    scenes.sort_by_key(|(scene_name, _)| *scene_name);
+
+    for (scene_name, scene) in scenes {
+        let mut device_ids: Vec<_> = scene.devices.keys().collect();
+        device_ids.sort();
+
+        for device_id in device_ids {
+            if !devices.contains_key(device_id) {
+                errors.push(format!(
+                    "Scene {} contains unknown device {}",
+                    scene_name, device_id
+                ));
+            }
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mqtt_config() -> MqttConfig {
+        config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                id = "tuya-mqtt"
+                host = "localhost"
+                port = 1883
+                topic = "home/lights/tuya/+"
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn device(id: &str, ip: &str) -> (String, TuyaDeviceConfig) {
+        (
+            id.to_string(),
+            TuyaDeviceConfig {
+                name: format!("Light {}", id),
+                id: id.to_string(),
+                local_key: "0123456789abcdef".to_string(),
+                ip: ip.to_string(),
+                version: "3.3".to_string(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env = config::Map::from([
+            ("TUYA_MQTT__MQTT__HOST".to_string(), "broker".to_string()),
+            ("TUYA_MQTT__MQTT__PORT".to_string(), "8883".to_string()),
+            ("TUYA_MQTT_CONFIG".to_string(), "ignored.toml".to_string()),
+        ]);
+
+        let config: Config = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                [mqtt]
+                id = "tuya-mqtt"
+                host = "localhost"
+                port = 1883
+                topic = "home/lights/tuya/+"
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .add_source(env_overrides().source(Some(env)))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        assert_eq!(config.mqtt.host, "broker");
+        assert_eq!(config.mqtt.port, 8883);
+    }
+
+    #[test]
+    fn test_read_devices_dir() {
+        let dir = std::env::temp_dir().join(format!("tuya-mqtt-devices-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("2526602070019412d1be.toml"),
+            r#"
+            name = "Downlight"
+            ip = "192.168.1.31"
+            version = "3.3"
+            local_key = "c24b690d5e1f0ab8"
+            "#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("README.md"), "Not a device").unwrap();
+
+        let devices = read_devices(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices["2526602070019412d1be"].name, "Downlight");
+    }
+
+    #[test]
+    fn test_local_key_sources() {
+        let device = |extra: &str| -> DeviceConfig {
+            config::Config::builder()
+                .add_source(config::File::from_str(
+                    &format!(
+                        "name = \"Light\"\nip = \"192.168.1.10\"\nversion = \"3.3\"\n{}",
+                        extra
+                    ),
+                    config::FileFormat::Toml,
+                ))
+                .build()
+                .unwrap()
+                .try_deserialize()
+                .unwrap()
+        };
+
+        let path = std::env::temp_dir().join(format!("tuya-mqtt-key-{}", std::process::id()));
+        std::fs::write(&path, "0123456789abcdef\n").unwrap();
+        let from_file = device(&format!("local_key_file = {:?}", path));
+        assert_eq!(from_file.local_key().unwrap(), "0123456789abcdef");
+        std::fs::remove_file(&path).unwrap();
+
+        // Not used by any other test, tests run in parallel
+        let var = "TUYA_MQTT_TEST_LOCAL_KEY_SOURCES";
+        std::env::set_var(var, "fedcba9876543210");
+        let from_env = device(&format!("local_key_env = {:?}", var));
+        assert_eq!(from_env.local_key().unwrap(), "fedcba9876543210");
+        std::env::remove_var(var);
+        assert!(from_env.local_key().is_err());
+
+        assert!(device("").local_key().is_err());
+        assert!(
+            device("local_key = \"0123456789abcdef\"\nlocal_key_env = \"KEY\"")
+                .local_key()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_clamp_ct() {
+        let mut device: DeviceConfig = serde_json::from_value(serde_json::json!({
+            "name": "Light",
+            "ip": "192.168.1.10",
+            "version": "3.3",
+            "local_key": "0123456789abcdef",
+            "capabilities": { "hs": false, "ct": { "start": 2000, "end": 6500 } },
+        }))
+        .unwrap();
+
+        clamp_ct("a", &mut device);
+        let ct = device.capabilities.as_ref().unwrap().ct.clone();
+        assert_eq!(ct, Some(MIN_SUPPORTED_CT..MAX_SUPPORTED_CT));
+
+        // Nothing of the range is supported, left for validate to report
+        device.capabilities.as_mut().unwrap().ct = Some(2000..2500);
+        clamp_ct("a", &mut device);
+        assert_eq!(device.capabilities.unwrap().ct, Some(2000..2500));
+    }
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let mut mqtt = mqtt_config();
+        mqtt.password = Some("hunter2".to_string());
+        assert!(!format!("{:?}", mqtt).contains("hunter2"));
+
+        let configured: DeviceConfig = serde_json::from_value(serde_json::json!({
+            "name": "Light",
+            "ip": "192.168.1.10",
+            "version": "3.3",
+            "local_key": "0123456789abcdef",
+        }))
+        .unwrap();
+        assert!(!format!("{:?}", configured).contains("0123456789abcdef"));
+
+        let (_, device) = device("a", "192.168.1.10");
+        assert!(!format!("{:?}", device).contains("0123456789abcdef"));
+    }
+
+    #[test]
+    fn test_validate_valid_config() {
+        let tuya_config = TuyaConfig {
+            devices: HashMap::from([device("a", "192.168.1.10"), device("b", "192.168.1.11")]),
+            ..Default::default()
+        };
+
+        assert!(validate(&mqtt_config(), &tuya_config).is_empty());
+    }
+
+    #[test]
+    fn test_unknown_devices() {
+        let groups = HashMap::from([(
+            "living_room".to_string(),
+            GroupConfig {
+                name: "Living room".to_string(),
+                members: vec!["a".to_string(), "b".to_string()],
+                topic: None,
+            },
+        )]);
+        let scenes = HashMap::from([(
+            "movie".to_string(),
+            SceneConfig {
+                devices: HashMap::from([("b".to_string(), Default::default())]),
+            },
+        )]);
+
+        let devices = HashMap::from([device("a", "192.168.1.10"), device("b", "192.168.1.11")]);
+        assert!(unknown_devices(&groups, &scenes, &devices).is_empty());
+
+        // Removing a device on reload leaves the running groups and scenes
+        // referring to it
+        let devices = HashMap::from([device("a", "192.168.1.10")]);
+        assert_eq!(
+            unknown_devices(&groups, &scenes, &devices),
+            vec![
+                "Group living_room contains unknown device b",
+                "Scene movie contains unknown device b",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_all_errors() {
+        let (_, mut a) = device("a", "192.168.1.300");
+        a.local_key = "0123456789abcde".to_string();
+        a.version = "3.2".to_string();
+        let (_, mut b) = device("b", "192.168.1.11");
+        b.topic = Some("home/lights/tuya/c".to_string());
+        b.allowed_dps = Some(vec![1, 7]);
+        b.denied_dps = Some(vec![7]);
+
+        let tuya_config = TuyaConfig {
+            devices: HashMap::from([
+                ("a".to_string(), a),
+                ("b".to_string(), b),
+                device("c", "192.168.1.11"),
+            ]),
+            ..Default::default()
+        };
+
+        let errors = validate(&mqtt_config(), &tuya_config);
+        assert_eq!(errors.len(), 6, "{:#?}", errors);
+        assert!(errors[0].starts_with("Device a (Light a): ip"));
+        let overlap = "Device b (Light b): DP 7 is both allowed and denied";
+        assert!(errors.iter().any(|e| e == overlap));
+        let duplicate_ip = "Device c (Light c): ip 192.168.1.11 is also used by device b";
+        assert!(errors.iter().any(|e| e == duplicate_ip));
+    }
+}
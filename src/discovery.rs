@@ -0,0 +1,236 @@
+//! Home Assistant MQTT discovery
+//!
+//! Publishes retained `<prefix>/<component>/<node_id>/<device_id>/config`
+//! messages for every configured device so Home Assistant picks them up
+//! without manual YAML. `node_id` is the bridge id from `MqttConfig`, which
+//! lets us find (and remove) entries that we published earlier for devices
+//! that have since been dropped from the config.
+//!
+//! Lights use the "template" schema, as that allows Home Assistant to speak
+//! the same JSON format as everybody else (see `MqttDevice`) instead of its
+//! own.
+
+use rumqttc::QoS;
+
+use crate::broker::BrokerClient;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::MqttConfig;
+use crate::mqtt::{device_availability_topic, device_topic, MAX_SUPPORTED_CT, MIN_SUPPORTED_CT};
+use crate::tuya::{DeviceType, TuyaConfig, TuyaDeviceConfig};
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct DiscoveryConfig {
+    /// Discovery topic prefix, Home Assistant defaults to "homeassistant"
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+fn default_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+const LIGHT_COMMAND_ON_TEMPLATE: &str = concat!(
+    r#"{"power":true"#,
+    r#"{% if brightness is defined %},"brightness":{{ brightness / 255 }}{% endif %}"#,
+    r#"{% if hue is defined and sat is defined %},"color":{"h":{{ hue | round | int }},"s":{{ sat / 100 }}}"#,
+    r#"{% elif color_temp is defined %},"color":{"ct":{{ color_temp | int }}}{% endif %}"#,
+    r#"{% if transition is defined %},"transition_ms":{{ transition * 1000 }}{% endif %}}"#,
+);
+
+const LIGHT_COMMAND_OFF_TEMPLATE: &str = r#"{"power":false}"#;
+
+const LIGHT_STATE_TEMPLATE: &str = "{{ 'on' if value_json.power else 'off' }}";
+
+const LIGHT_BRIGHTNESS_TEMPLATE: &str = concat!(
+    "{% if value_json.brightness is number %}",
+    "{{ (value_json.brightness * 255) | round | int }}",
+    "{% else %}None{% endif %}",
+);
+
+const LIGHT_COLOR_TEMP_TEMPLATE: &str = concat!(
+    "{% if value_json.color is mapping and value_json.color.ct is defined %}",
+    "{{ value_json.color.ct }}",
+    "{% else %}None{% endif %}",
+);
+
+/// HSV (with V = 1) to RGB conversion, `<n>` is 5 for red, 3 for green and 1
+/// for blue
+const LIGHT_RGB_CHANNEL_TEMPLATE: &str = concat!(
+    "{% if value_json.color is mapping and value_json.color.h is defined %}",
+    "{% set k = (<n> + value_json.color.h / 60) % 6 %}",
+    "{{ (255 * (1 - value_json.color.s * ([0, [k, 4 - k, 1] | min] | max))) | round | int }}",
+    "{% else %}None{% endif %}",
+);
+
+impl DeviceType {
+    /// Home Assistant component name
+    fn component(&self) -> &'static str {
+        match self {
+            DeviceType::Light => "light",
+            DeviceType::Switch => "switch",
+            DeviceType::Sensor => "sensor",
+            DeviceType::Cover => "cover",
+        }
+    }
+}
+
+fn discovery_topic(
+    discovery_config: &DiscoveryConfig,
+    mqtt_config: &MqttConfig,
+    device: &TuyaDeviceConfig,
+) -> String {
+    format!(
+        "{}/{}/{}/{}/config",
+        discovery_config.prefix,
+        device.device_type.component(),
+        mqtt_config.id,
+        device.id
+    )
+}
+
+fn discovery_payload(mqtt_config: &MqttConfig, device: &TuyaDeviceConfig) -> Value {
+    let state_topic = device_topic(&mqtt_config.topic, device);
+    let command_topic = format!("{}/set", state_topic);
+    let power_payload = |power: bool| json!({ "power": power }).to_string();
+
+    let mut payload = json!({
+        "name": null,
+        "unique_id": format!("tuya_{}", device.id),
+        "state_topic": state_topic,
+        "availability": [
+            { "topic": mqtt_config.availability_topic() },
+            { "topic": device_availability_topic(&mqtt_config.topic, device) },
+        ],
+        "availability_mode": "all",
+        "device": {
+            "identifiers": [format!("tuya_{}", device.id)],
+            "name": device.name,
+            "manufacturer": "Tuya",
+            "sw_version": device.version,
+        },
+    });
+
+    let component = match device.device_type {
+        DeviceType::Light => {
+            let capabilities = device.capabilities.clone().unwrap_or_default();
+            let mut light = json!({
+                "schema": "template",
+                "command_topic": command_topic,
+                "command_on_template": LIGHT_COMMAND_ON_TEMPLATE,
+                "command_off_template": LIGHT_COMMAND_OFF_TEMPLATE,
+                "state_template": LIGHT_STATE_TEMPLATE,
+                "brightness_template": LIGHT_BRIGHTNESS_TEMPLATE,
+            });
+
+            if let Some(ct) = capabilities.ct {
+                light["color_temp_kelvin"] = json!(true);
+                light["color_temp_template"] = json!(LIGHT_COLOR_TEMP_TEMPLATE);
+                light["min_kelvin"] = json!(ct.start.max(MIN_SUPPORTED_CT));
+                light["max_kelvin"] = json!(ct.end.min(MAX_SUPPORTED_CT));
+            }
+
+            if capabilities.hs {
+                for (key, n) in [("red", 5), ("green", 3), ("blue", 1)] {
+                    light[format!("{}_template", key)] =
+                        json!(LIGHT_RGB_CHANNEL_TEMPLATE.replace("<n>", &n.to_string()));
+                }
+            }
+
+            light
+        }
+        DeviceType::Switch => json!({
+            "command_topic": command_topic,
+            "payload_on": power_payload(true),
+            "payload_off": power_payload(false),
+            "value_template": "{{ 'ON' if value_json.power else 'OFF' }}",
+            "state_on": "ON",
+            "state_off": "OFF",
+        }),
+        DeviceType::Sensor => json!({
+            "value_template": "{{ value_json.sensor_value }}",
+        }),
+        DeviceType::Cover => {
+            let cover = device.cover.clone().unwrap_or_default();
+            let dps_payload = |value: &Value| json!({ "dps": { &cover.field: value } }).to_string();
+
+            // JSON scalars are valid Jinja literals
+            json!({
+                "command_topic": command_topic,
+                "payload_open": dps_payload(&cover.open),
+                "payload_close": dps_payload(&cover.close),
+                "payload_stop": cover.stop.as_ref().map(dps_payload),
+                "value_template": format!(
+                    "{{% set value = value_json.raw['{}'] %}}{{{{ 'open' if value == {} else 'closed' if value == {} else 'stopped' }}}}",
+                    cover.field, cover.open, cover.close
+                ),
+            })
+        }
+    };
+
+    if let (Value::Object(payload), Value::Object(component)) = (&mut payload, component) {
+        payload.extend(component);
+    }
+
+    payload
+}
+
+/// Topic filter matching every discovery entry published by this bridge
+pub fn discovery_subscription(
+    discovery_config: &DiscoveryConfig,
+    mqtt_config: &MqttConfig,
+) -> String {
+    format!("{}/+/{}/+/config", discovery_config.prefix, mqtt_config.id)
+}
+
+/// Publish retained discovery entries for all configured devices
+pub async fn publish_discovery(
+    client: &BrokerClient,
+    discovery_config: &DiscoveryConfig,
+    mqtt_config: &MqttConfig,
+    tuya_config: &TuyaConfig,
+) {
+    for device in tuya_config.devices.values() {
+        let topic = discovery_topic(discovery_config, mqtt_config, device);
+        let payload = discovery_payload(mqtt_config, device).to_string();
+
+        let res = client
+            .publish(&topic, mqtt_config.publish_qos(), true, payload)
+            .await;
+
+        if let Err(e) = res {
+            eprintln!("Could not publish discovery entry {}: {:?}", topic, e);
+        }
+    }
+}
+
+/// Returns true if `topic` is a discovery entry published by this bridge
+/// that does not correspond to a currently configured device
+pub fn is_stale_discovery_topic(
+    topic: &str,
+    discovery_config: &DiscoveryConfig,
+    mqtt_config: &MqttConfig,
+    tuya_config: &TuyaConfig,
+) -> bool {
+    let Some(rest) = topic.strip_prefix(&format!("{}/", discovery_config.prefix)) else {
+        return false;
+    };
+
+    match rest.split('/').collect::<Vec<_>>()[..] {
+        [_component, node_id, _device_id, "config"] if node_id == mqtt_config.id => !tuya_config
+            .devices
+            .values()
+            .any(|device| discovery_topic(discovery_config, mqtt_config, device) == topic),
+        _ => false,
+    }
+}
+
+/// Remove a (retained) discovery entry from the broker
+pub async fn remove_discovery(client: &BrokerClient, qos: QoS, topic: &str) {
+    let res = client.publish(topic, qos, true, vec![]).await;
+
+    if let Err(e) = res {
+        eprintln!("Could not remove discovery entry {}: {:?}", topic, e);
+    }
+}
@@ -0,0 +1,360 @@
+//! Device groups
+//!
+//! A group has its own topic, `/set` messages on it are fanned out to every
+//! member device, and the group publishes an aggregated state of its members
+//! (on if any member is on, average brightness of members that are on).
+
+use log::warn;
+use rumqttc::QoS;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{timeout, timeout_at, Instant};
+
+use crate::broker::{BrokerClient, ReplyTo};
+use crate::mqtt::{DeviceColor, MqttDevice};
+use crate::tuya::CommandResult;
+
+/// How long members of a group command wait for each other before sending
+/// anyway, e.g. when a member is busy or disconnected
+const SYNC_MAX_WAIT_MS: u64 = 2_000;
+
+/// How long to wait for results of all members of a group command
+const GROUP_RESULT_TIMEOUT_MS: u64 = 15_000;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GroupConfig {
+    pub name: String,
+    /// Member device ids
+    pub members: Vec<String>,
+    pub topic: Option<String>,
+}
+
+/// Topic where group state is published, commands are received on the same
+/// topic with a `/set` suffix
+pub fn group_topic(base_topic: &str, group_id: &str, group: &GroupConfig) -> String {
+    group
+        .topic
+        .clone()
+        .unwrap_or_else(|| base_topic.replacen('+', group_id, 1))
+}
+
+/// Aggregated state of the given member states
+pub fn aggregate_state(group_id: &str, group: &GroupConfig, states: &[&MqttDevice]) -> MqttDevice {
+    let on: Vec<_> = states
+        .iter()
+        .filter(|state| state.power == Some(true))
+        .collect();
+
+    let brightness: Vec<f32> = on.iter().filter_map(|state| state.brightness).collect();
+    let brightness = if brightness.is_empty() {
+        None
+    } else {
+        Some(brightness.iter().sum::<f32>() / brightness.len() as f32)
+    };
+
+    // Only report a color if all members agree on it
+    let color: Option<DeviceColor> = match states.first() {
+        Some(first) if states.iter().all(|state| state.color == first.color) => first.color.clone(),
+        _ => None,
+    };
+
+    MqttDevice {
+        id: Some(group_id.to_string()),
+        name: Some(group.name.clone()),
+        power: if states.is_empty() {
+            None
+        } else {
+            Some(!on.is_empty())
+        },
+        brightness,
+        color,
+        ..Default::default()
+    }
+}
+
+/// Lets the members of a group command send their commands at the same
+/// instant. Every member waits until all members are ready to send, or until
+/// a deadline shared by all members.
+#[derive(Debug)]
+pub struct SyncGate {
+    members: usize,
+    ready: watch::Sender<usize>,
+    deadline: Instant,
+}
+
+impl SyncGate {
+    pub fn new(members: usize) -> Self {
+        Self {
+            members,
+            ready: watch::channel(0).0,
+            deadline: Instant::now() + Duration::from_millis(SYNC_MAX_WAIT_MS),
+        }
+    }
+
+    /// Called by each member right before sending its command
+    pub async fn wait(&self) {
+        let mut ready = self.ready.subscribe();
+        self.ready.send_modify(|ready| *ready += 1);
+
+        let all_ready = ready.wait_for(|ready| *ready >= self.members);
+        let _ = timeout_at(self.deadline, all_ready).await;
+    }
+}
+
+/// Group context of a member's command
+#[derive(Clone, Debug)]
+pub struct GroupRequest {
+    pub gate: Arc<SyncGate>,
+    /// Receives the result of every member's command
+    pub results: mpsc::UnboundedSender<(String, CommandResult)>,
+}
+
+/// Prepare a synchronised command for all members of a group. Once every
+/// member has reported back (or after a timeout) the combined result is
+/// published on `<group topic>/result`, and as an MQTT 5 response if
+/// requested. `missing` members aren't sent the command and are reported as
+/// failed.
+#[allow(clippy::too_many_arguments)]
+pub fn start_group_request(
+    client: BrokerClient,
+    qos: QoS,
+    group_id: String,
+    group_topic: String,
+    members: Vec<String>,
+    missing: Vec<String>,
+    request_id: Option<String>,
+    reply_to: Option<ReplyTo>,
+) -> GroupRequest {
+    let (results_tx, mut results_rx) = mpsc::unbounded_channel();
+    let request = GroupRequest {
+        gate: Arc::new(SyncGate::new(members.len())),
+        results: results_tx,
+    };
+
+    tokio::spawn(async move {
+        let mut results = HashMap::new();
+
+        let collect = async {
+            while results.len() < members.len() {
+                match results_rx.recv().await {
+                    Some((id, result)) => {
+                        results.insert(id, result);
+                    }
+                    None => break,
+                }
+            }
+        };
+        let _ = timeout(Duration::from_millis(GROUP_RESULT_TIMEOUT_MS), collect).await;
+
+        let payload = group_result(&group_id, request_id, &members, &missing, &results).to_string();
+
+        let result_topic = format!("{}/result", group_topic);
+        if let Err(e) = client
+            .publish(result_topic, qos, false, payload.clone())
+            .await
+        {
+            warn!("Error publishing command result for {}: {:?}", group_id, e);
+        }
+
+        if let Some(reply_to) = reply_to {
+            let res = client.publish_response(&group_id, &reply_to, payload).await;
+            if let Err(e) = res {
+                warn!("Error publishing MQTT response for {}: {:?}", group_id, e);
+            }
+        }
+    });
+
+    request
+}
+
+/// Combined result of a group command
+fn group_result(
+    group_id: &str,
+    request_id: Option<String>,
+    members: &[String],
+    missing: &[String],
+    results: &HashMap<String, CommandResult>,
+) -> Value {
+    let mut member_results: serde_json::Map<String, Value> = members
+        .iter()
+        .map(|member| {
+            let result = match results.get(member) {
+                Some(result) => json!(result),
+                None => json!({ "success": false, "error": "No result from device" }),
+            };
+            (member.clone(), result)
+        })
+        .collect();
+    for member in missing {
+        member_results.insert(
+            member.clone(),
+            json!({ "success": false, "error": "Device is not configured" }),
+        );
+    }
+
+    json!({
+        "id": group_id,
+        "request_id": request_id,
+        "success": missing.is_empty()
+            && members
+                .iter()
+                .all(|member| results.get(member).is_some_and(|result| result.success)),
+        "members": member_results,
+    })
+}
+
+/// Tracks member states of all groups
+pub struct Groups {
+    base_topic: String,
+    groups: HashMap<String, GroupConfig>,
+    /// Last state of every device that belongs to a group
+    member_states: Mutex<HashMap<String, MqttDevice>>,
+    /// Last aggregated state of every group
+    group_states: Mutex<HashMap<String, MqttDevice>>,
+}
+
+impl Groups {
+    pub fn new(base_topic: &str, groups: HashMap<String, GroupConfig>) -> Self {
+        Self {
+            base_topic: base_topic.to_string(),
+            groups,
+            member_states: Mutex::new(HashMap::new()),
+            group_states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record the new state of a device, returns topics and aggregated states
+    /// of the groups whose state changed as a result
+    pub fn update(&self, device_id: &str, state: &MqttDevice) -> Vec<(String, MqttDevice)> {
+        if !self
+            .groups
+            .values()
+            .any(|group| group.members.iter().any(|member| member == device_id))
+        {
+            return vec![];
+        }
+
+        let mut member_states = self.member_states.lock().unwrap();
+        member_states.insert(device_id.to_string(), state.clone());
+
+        let mut group_states = self.group_states.lock().unwrap();
+        let mut changed = vec![];
+
+        for (group_id, group) in &self.groups {
+            if !group.members.iter().any(|member| member == device_id) {
+                continue;
+            }
+
+            let states: Vec<_> = group
+                .members
+                .iter()
+                .filter_map(|member| member_states.get(member))
+                .collect();
+            let aggregated = aggregate_state(group_id, group, &states);
+
+            if group_states.get(group_id) != Some(&aggregated) {
+                group_states.insert(group_id.clone(), aggregated.clone());
+                changed.push((group_topic(&self.base_topic, group_id, group), aggregated));
+            }
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn light(power: bool, brightness: f32) -> MqttDevice {
+        MqttDevice {
+            power: Some(power),
+            brightness: Some(brightness),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_aggregate_state() {
+        let group = GroupConfig {
+            name: "Living room".to_string(),
+            members: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            topic: None,
+        };
+
+        let (a, b, c) = (light(true, 0.2), light(true, 0.6), light(false, 1.0));
+        let state = aggregate_state("living_room", &group, &[&a, &b, &c]);
+        assert_eq!(state.power, Some(true));
+        assert!((state.brightness.unwrap() - 0.4).abs() < 0.001);
+
+        let state = aggregate_state("living_room", &group, &[&c]);
+        assert_eq!(state.power, Some(false));
+        assert_eq!(state.brightness, None);
+    }
+
+    #[test]
+    fn test_group_result_reports_missing_members() {
+        let members = vec!["a".to_string()];
+        let missing = vec!["b".to_string()];
+        let results = HashMap::from([(
+            "a".to_string(),
+            CommandResult {
+                success: true,
+                dps: json!({"20": true}),
+                latency_ms: 10,
+                error: None,
+            },
+        )]);
+
+        let result = group_result("living_room", None, &members, &missing, &results);
+        assert_eq!(result["success"], json!(false));
+        assert_eq!(result["members"]["a"]["success"], json!(true));
+        assert_eq!(
+            result["members"]["b"]["error"],
+            json!("Device is not configured")
+        );
+
+        let result = group_result("living_room", None, &members, &[], &results);
+        assert_eq!(result["success"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn test_sync_gate_releases_when_all_members_are_ready() {
+        let gate = Arc::new(SyncGate::new(2));
+
+        let first = tokio::spawn({
+            let gate = gate.clone();
+            async move { gate.wait().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!first.is_finished());
+
+        gate.wait().await;
+        first.await.unwrap();
+        assert!(Instant::now() < gate.deadline);
+    }
+
+    #[test]
+    fn test_update_only_reports_changes() {
+        let group = GroupConfig {
+            name: "Living room".to_string(),
+            members: vec!["a".to_string(), "b".to_string()],
+            topic: None,
+        };
+        let groups = Groups::new(
+            "home/lights/tuya/+",
+            HashMap::from([("living_room".to_string(), group)]),
+        );
+
+        let changed = groups.update("a", &light(true, 0.5));
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, "home/lights/tuya/living_room");
+
+        assert!(groups.update("a", &light(true, 0.5)).is_empty());
+        assert!(groups.update("other", &light(true, 0.5)).is_empty());
+    }
+}
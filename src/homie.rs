@@ -0,0 +1,410 @@
+//! Homie 4 convention (https://homieiot.github.io/specification/)
+//!
+//! Optionally publishes each device attribute as a plain value on its own
+//! topic, e.g. `homie/<device_id>/light/power = true`, along with the Homie
+//! `$`-prefixed metadata describing the available properties. Commands are
+//! accepted on the per-property `/set` topics and translated into the same
+//! partial `MqttDevice` updates as JSON `/set` messages.
+
+use anyhow::{anyhow, Context, Result};
+use rumqttc::QoS;
+
+use crate::broker::BrokerClient;
+use serde::Deserialize;
+
+use crate::mqtt::{DeviceColor, Hs, MqttDevice, MAX_SUPPORTED_CT, MIN_SUPPORTED_CT};
+use crate::tuya::{DeviceType, TuyaConfig, TuyaDeviceConfig};
+
+const HOMIE_VERSION: &str = "4.0";
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct HomieConfig {
+    /// Homie base topic, defaults to "homie"
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+fn default_prefix() -> String {
+    "homie".to_string()
+}
+
+/// Homie device state, see the `$state` device attribute
+pub enum HomieState {
+    Ready,
+    /// Stopped on purpose, on shutdown or when removed from the config
+    Disconnected,
+    Lost,
+}
+
+impl HomieState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HomieState::Ready => "ready",
+            HomieState::Disconnected => "disconnected",
+            HomieState::Lost => "lost",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Property {
+    Power,
+    Brightness,
+    Color,
+    ColorTemperature,
+    SensorValue,
+}
+
+impl Property {
+    fn id(&self) -> &'static str {
+        match self {
+            Property::Power => "power",
+            Property::Brightness => "brightness",
+            Property::Color => "color",
+            Property::ColorTemperature => "color-temperature",
+            Property::SensorValue => "sensor-value",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Property::Power => "Power",
+            Property::Brightness => "Brightness",
+            Property::Color => "Color",
+            Property::ColorTemperature => "Color temperature",
+            Property::SensorValue => "Sensor value",
+        }
+    }
+
+    /// Property attributes ($name, $datatype, ...) as (attribute, value) pairs
+    fn attributes(&self, device: &TuyaDeviceConfig) -> Vec<(&'static str, String)> {
+        let mut attributes = vec![("$name", self.name().to_string())];
+
+        match self {
+            Property::Power => attributes.push(("$datatype", "boolean".to_string())),
+            Property::Brightness => {
+                attributes.push(("$datatype", "float".to_string()));
+                attributes.push(("$format", "0:1".to_string()));
+            }
+            Property::Color => {
+                attributes.push(("$datatype", "color".to_string()));
+                attributes.push(("$format", "hsv".to_string()));
+            }
+            Property::ColorTemperature => {
+                let ct = ct_range(device);
+                attributes.push(("$datatype", "integer".to_string()));
+                attributes.push(("$format", format!("{}:{}", ct.start, ct.end)));
+                attributes.push(("$unit", "K".to_string()));
+            }
+            Property::SensorValue => attributes.push(("$datatype", "string".to_string())),
+        }
+
+        let settable = *self != Property::SensorValue;
+        attributes.push(("$settable", settable.to_string()));
+        attributes.push(("$retained", "true".to_string()));
+
+        attributes
+    }
+
+    fn from_id(id: &str) -> Option<Property> {
+        [
+            Property::Power,
+            Property::Brightness,
+            Property::Color,
+            Property::ColorTemperature,
+            Property::SensorValue,
+        ]
+        .into_iter()
+        .find(|property| property.id() == id)
+    }
+}
+
+fn ct_range(device: &TuyaDeviceConfig) -> std::ops::Range<u16> {
+    let capabilities = device.capabilities.clone().unwrap_or_default();
+    let ct = capabilities
+        .ct
+        .unwrap_or(MIN_SUPPORTED_CT..MAX_SUPPORTED_CT);
+    ct.start.max(MIN_SUPPORTED_CT)..ct.end.min(MAX_SUPPORTED_CT)
+}
+
+/// Homie ids may only contain lowercase letters, digits and hyphens
+fn homie_id(device: &TuyaDeviceConfig) -> String {
+    device.id.to_lowercase()
+}
+
+fn node_id(device: &TuyaDeviceConfig) -> &'static str {
+    match device.device_type {
+        DeviceType::Light => "light",
+        DeviceType::Switch => "switch",
+        DeviceType::Sensor => "sensor",
+        DeviceType::Cover => "cover",
+    }
+}
+
+fn properties(device: &TuyaDeviceConfig) -> Vec<Property> {
+    match device.device_type {
+        DeviceType::Light => {
+            let capabilities = device.capabilities.clone().unwrap_or_default();
+            let mut properties = vec![Property::Power, Property::Brightness];
+            if capabilities.hs {
+                properties.push(Property::Color);
+            }
+            if capabilities.ct.is_some() {
+                properties.push(Property::ColorTemperature);
+            }
+            properties
+        }
+        DeviceType::Switch | DeviceType::Cover => vec![Property::Power],
+        DeviceType::Sensor => vec![Property::SensorValue],
+    }
+}
+
+fn device_topic(homie_config: &HomieConfig, device: &TuyaDeviceConfig) -> String {
+    format!("{}/{}", homie_config.prefix, homie_id(device))
+}
+
+fn node_topic(homie_config: &HomieConfig, device: &TuyaDeviceConfig) -> String {
+    format!("{}/{}", device_topic(homie_config, device), node_id(device))
+}
+
+/// Topic filter matching the property `/set` topics of a device
+pub fn set_subscription(homie_config: &HomieConfig, device: &TuyaDeviceConfig) -> String {
+    format!("{}/+/set", node_topic(homie_config, device))
+}
+
+/// Device, node and property attributes describing a device
+fn metadata_messages(
+    homie_config: &HomieConfig,
+    device: &TuyaDeviceConfig,
+) -> Vec<(String, String)> {
+    let device_topic = device_topic(homie_config, device);
+    let node_topic = node_topic(homie_config, device);
+    let properties = properties(device);
+
+    let mut messages = vec![
+        (
+            format!("{}/$homie", device_topic),
+            HOMIE_VERSION.to_string(),
+        ),
+        (format!("{}/$name", device_topic), device.name.clone()),
+        (
+            format!("{}/$nodes", device_topic),
+            node_id(device).to_string(),
+        ),
+        (format!("{}/$name", node_topic), device.name.clone()),
+        (format!("{}/$type", node_topic), node_id(device).to_string()),
+        (
+            format!("{}/$properties", node_topic),
+            properties
+                .iter()
+                .map(Property::id)
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+    ];
+
+    for property in properties {
+        for (attribute, value) in property.attributes(device) {
+            messages.push((
+                format!("{}/{}/{}", node_topic, property.id(), attribute),
+                value,
+            ));
+        }
+    }
+
+    messages
+}
+
+/// Property values of a device state, properties without a known value are
+/// left out
+pub fn state_messages(
+    homie_config: &HomieConfig,
+    device: &TuyaDeviceConfig,
+    mqtt_device: &MqttDevice,
+) -> Vec<(String, String)> {
+    let node_topic = node_topic(homie_config, device);
+
+    properties(device)
+        .into_iter()
+        .filter_map(|property| {
+            let value = match property {
+                Property::Power => mqtt_device.power.map(|power| power.to_string()),
+                Property::Brightness => mqtt_device
+                    .brightness
+                    .map(|brightness| brightness.to_string()),
+                Property::Color => match &mqtt_device.color {
+                    Some(DeviceColor::Hs(hs)) => {
+                        Some(format!("{},{},100", hs.h, (hs.s * 100.0).round()))
+                    }
+                    _ => None,
+                },
+                Property::ColorTemperature => match &mqtt_device.color {
+                    Some(DeviceColor::Ct(ct)) => Some(ct.ct.to_string()),
+                    _ => None,
+                },
+                Property::SensorValue => mqtt_device.sensor_value.clone(),
+            }?;
+
+            Some((format!("{}/{}", node_topic, property.id()), value))
+        })
+        .collect()
+}
+
+/// Topic of the `$state` device attribute
+pub fn state_attribute_topic(homie_config: &HomieConfig, device: &TuyaDeviceConfig) -> String {
+    format!("{}/$state", device_topic(homie_config, device))
+}
+
+/// Parse a message on a property `/set` topic into the id of the targeted
+/// device and a partial device update. Returns None if the topic is not a
+/// Homie `/set` topic of any configured device.
+pub fn parse_set_message(
+    homie_config: &HomieConfig,
+    tuya_config: &TuyaConfig,
+    topic: &str,
+    payload: &[u8],
+) -> Option<Result<(String, MqttDevice)>> {
+    let device = tuya_config.devices.values().find(|device| {
+        topic
+            .strip_prefix(&node_topic(homie_config, device))
+            .is_some_and(|rest| rest.starts_with('/') && rest.ends_with("/set"))
+    })?;
+
+    let property_id = topic
+        .strip_prefix(&format!("{}/", node_topic(homie_config, device)))?
+        .strip_suffix("/set")?;
+
+    Some(parse_property_value(device, property_id, payload))
+}
+
+fn parse_property_value(
+    device: &TuyaDeviceConfig,
+    property_id: &str,
+    payload: &[u8],
+) -> Result<(String, MqttDevice)> {
+    let property = Property::from_id(property_id)
+        .filter(|property| properties(device).contains(property))
+        .with_context(|| format!("Unknown property {} for {}", property_id, device.name))?;
+
+    let value = std::str::from_utf8(payload)?.trim();
+
+    let mut mqtt_device = MqttDevice {
+        id: Some(device.id.clone()),
+        ..Default::default()
+    };
+
+    match property {
+        Property::Power => mqtt_device.power = Some(value.parse()?),
+        Property::Brightness => {
+            mqtt_device.brightness = Some(value.parse::<f32>()?.clamp(0.0, 1.0))
+        }
+        Property::Color => {
+            // Brightness is controlled through its own property, so the V
+            // component is ignored
+            let hsv = value
+                .split(',')
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<_>, _>>()?;
+            let [h, s, ..] = hsv[..] else {
+                return Err(anyhow!("Expected hsv color, got {}", value));
+            };
+            mqtt_device.color = Some(DeviceColor::Hs(Hs {
+                h: h.clamp(0.0, 360.0) as u16,
+                s: s.clamp(0.0, 100.0) / 100.0,
+            }));
+        }
+        Property::ColorTemperature => {
+            let ct = ct_range(device);
+            let value = value.parse::<u16>()?.clamp(ct.start, ct.end);
+            mqtt_device.color = Some(DeviceColor::Ct(crate::mqtt::Ct { ct: value }));
+        }
+        Property::SensorValue => return Err(anyhow!("{} is not settable", property_id)),
+    }
+
+    Ok((device.id.clone(), mqtt_device))
+}
+
+/// Publish retained metadata for all configured devices
+pub async fn publish_metadata(
+    client: &BrokerClient,
+    qos: QoS,
+    homie_config: &HomieConfig,
+    tuya_config: &TuyaConfig,
+) {
+    for device in tuya_config.devices.values() {
+        for (topic, payload) in metadata_messages(homie_config, device) {
+            let res = client.publish(&topic, qos, true, payload).await;
+
+            if let Err(e) = res {
+                eprintln!("Could not publish Homie attribute {}: {:?}", topic, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config() -> (HomieConfig, TuyaConfig) {
+        let device = TuyaDeviceConfig {
+            name: "Downlight".to_string(),
+            id: "bf12ab".to_string(),
+            ..Default::default()
+        };
+        let devices = HashMap::from([(device.id.clone(), device)]);
+
+        (
+            HomieConfig {
+                prefix: default_prefix(),
+            },
+            TuyaConfig {
+                devices,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_parse_set_message() {
+        let (homie_config, tuya_config) = config();
+
+        let (id, device) = parse_set_message(
+            &homie_config,
+            &tuya_config,
+            "homie/bf12ab/light/power/set",
+            b"false",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(id, "bf12ab");
+        assert_eq!(device.power, Some(false));
+        assert_eq!(device.brightness, None);
+
+        let (_, device) = parse_set_message(
+            &homie_config,
+            &tuya_config,
+            "homie/bf12ab/light/color/set",
+            b"120,50,100",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(device.color, Some(DeviceColor::Hs(Hs { h: 120, s: 0.5 })));
+    }
+
+    #[test]
+    fn test_parse_set_message_rejects_unknown_topics() {
+        let (homie_config, tuya_config) = config();
+
+        assert!(parse_set_message(&homie_config, &tuya_config, "home/bf12ab/set", b"{}").is_none());
+        assert!(parse_set_message(
+            &homie_config,
+            &tuya_config,
+            "homie/bf12ab/light/sensor-value/set",
+            b"1"
+        )
+        .unwrap()
+        .is_err());
+    }
+}
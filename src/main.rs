@@ -1,25 +1,122 @@
-use crate::config::read_config_devices;
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use crate::config::{config_file, create_sample_config, read_config_devices, unknown_devices};
 use crate::mqtt::init_mqtt;
-use crate::tuya::init_tuya;
+use crate::reload::{ConfigWatcher, DeviceTasks};
 
+mod brightness;
+mod broker;
 mod config;
+mod discovery;
+mod group;
+mod homie;
 mod mqtt;
+mod reload;
+mod scene;
+mod state;
+mod tinytuya;
 mod tuya;
 mod tuyapi;
 
+/// How long to wait for the MQTT disconnect to be sent on shutdown
+const MQTT_SHUTDOWN_TIMEOUT_MS: u64 = 2_000;
+
+/// Wait for SIGINT (Ctrl-C) or SIGTERM
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = sigterm.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
+    create_sample_config()?;
     let (mqtt_config, tuya_config) = read_config_devices()?;
+    tuyapi::set_log_secrets(tuya_config.unsafe_log_secrets);
     let mqtt_client = init_mqtt(&mqtt_config, &tuya_config).await?;
 
+    let mut devices = DeviceTasks::new(mqtt_client.clone());
     for device in tuya_config.devices.into_values() {
-        let mqtt_client = mqtt_client.clone();
-        init_tuya(device, mqtt_client).await;
+        devices.start(device).await;
     }
 
-    tokio::signal::ctrl_c().await?;
+    let watched_paths = [
+        Some(config_file()),
+        tuya_config.devices_path,
+        tuya_config.tinytuya_devices,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let mut config_watcher = ConfigWatcher::new(watched_paths)?;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            res = &mut shutdown => {
+                res?;
+                break;
+            }
+            _ = config_watcher.changed() => {
+                // Changes to the MQTT connection, groups and scenes require a
+                // restart. A missing or unreadable file keeps the current devices
+                match read_config_devices() {
+                    Ok((_, new_config)) => {
+                        // Running groups and scenes must not lose their devices
+                        let errors = unknown_devices(
+                            &tuya_config.groups,
+                            &tuya_config.scenes,
+                            &new_config.devices,
+                        );
+                        if errors.is_empty() {
+                            tuyapi::set_log_secrets(new_config.unsafe_log_secrets);
+                            devices.reload(&mqtt_config, new_config.devices).await;
+                        } else {
+                            eprintln!(
+                                "Could not reload config, keeping current devices. Groups and scenes are only reloaded on restart:\n  - {}",
+                                errors.join("\n  - ")
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Could not reload config, keeping current devices: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    println!("Shutting down...");
+
+    // Devices finish queued commands, disconnect and publish offline availability
+    devices.stop_all().await;
+
+    if let Err(e) = mqtt_client.states.save() {
+        eprintln!("Could not save state cache: {:?}", e);
+    }
+
+    let mqtt_stopped = mqtt_client.disconnect(&mqtt_config);
+    let mqtt_timeout = Duration::from_millis(MQTT_SHUTDOWN_TIMEOUT_MS);
+    match timeout(mqtt_timeout, mqtt_stopped).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("Could not disconnect from MQTT broker: {:?}", e),
+        Err(_) => eprintln!("Timed out disconnecting from MQTT broker"),
+    }
 
     Ok(())
 }
@@ -1,17 +1,32 @@
 #![allow(clippy::redundant_closure_call)]
 
-use anyhow::{Context, Result};
-use rand::distr::{Alphanumeric, SampleString};
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use anyhow::{anyhow, Context, Result};
+use rumqttc::{QoS, TlsConfiguration, Transport};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
-    sync::{watch::Receiver, RwLock},
+    sync::{watch, Notify},
     task,
 };
 
+use crate::broker::{BrokerClient, BrokerEvent, BrokerOptions, ReplyTo};
 use crate::config::MqttConfig;
-use crate::tuya::TuyaConfig;
+use crate::discovery;
+use crate::group::{self, group_topic, GroupRequest, Groups};
+use crate::homie::{self, HomieConfig};
+use crate::scene::{self, SceneAction, Scenes};
+use crate::state::{self, StateCache};
+use crate::tuya::{TuyaConfig, TuyaDeviceConfig};
+
+/// Most requests queued per device, the oldest is dropped once full. Plain
+/// `/set` requests are merged, only requests that are answered individually
+/// take up more than one slot.
+const MAX_PENDING_COMMANDS: usize = 32;
 
 // Assume (probably incorrectly) that supported range is from 2700K - 6500K
 pub const MIN_SUPPORTED_CT: u16 = 2700;
@@ -23,7 +38,7 @@ pub struct Capabilities {
     #[serde(default)]
     pub hs: bool,
 
-    /// Color temperature (2000 - 6500)
+    /// Color temperature range in Kelvin, clamped to 2700 - 6500
     pub ct: Option<std::ops::Range<u16>>,
 }
 
@@ -54,9 +69,11 @@ pub enum DeviceColor {
     Ct(Ct),
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 pub struct MqttDevice {
-    pub id: String,
+    /// Optional in `/set` messages, the device is identified by topic
+    #[serde(default)]
+    pub id: Option<String>,
     pub name: Option<String>,
     pub power: Option<bool>,
     pub brightness: Option<f32>,
@@ -65,86 +82,732 @@ pub struct MqttDevice {
     pub sensor_value: Option<String>,
     pub capabilities: Option<Capabilities>,
     pub raw: Option<serde_json::Value>,
+    /// Optional in `/set` messages, echoed back in the command result
+    #[serde(default, skip_serializing)]
+    pub request_id: Option<String>,
+    /// DPs to set as is, in addition to the fields above
+    #[serde(default, skip_serializing)]
+    pub dps: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Read the current state from the device, `/set` messages may consist
+    /// of only `{"refresh": true}`
+    #[serde(default, skip_serializing)]
+    pub refresh: bool,
+    /// Set on the last known state published at startup, until the device
+    /// reports its actual state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stale: Option<bool>,
+}
+
+/// Topic where device state is published, commands are received on the same
+/// topic with a `/set` suffix
+pub fn device_topic(base_topic: &str, device: &TuyaDeviceConfig) -> String {
+    device
+        .topic
+        .clone()
+        .unwrap_or_else(|| base_topic.replacen('+', &device.id, 1))
+}
+
+/// Topic where the outcome of every `/set` command is published
+pub fn device_result_topic(base_topic: &str, device: &TuyaDeviceConfig) -> String {
+    format!("{}/result", device_topic(base_topic, device))
+}
+
+/// Per-device availability topic, contains either "online" or "offline"
+pub fn device_availability_topic(base_topic: &str, device: &TuyaDeviceConfig) -> String {
+    format!("{}/availability", device_topic(base_topic, device))
+}
+
+pub const AVAILABILITY_ONLINE: &str = "online";
+pub const AVAILABILITY_OFFLINE: &str = "offline";
+
+/// A `/set` request for a device
+#[derive(Clone, Debug)]
+pub struct CommandRequest {
+    pub device: MqttDevice,
+    /// Where to send the result of the command, if the requester asked for it
+    pub reply_to: Option<ReplyTo>,
+    /// Set if the command was sent to a group
+    pub group: Option<GroupRequest>,
+}
+
+impl CommandRequest {
+    /// Requests that are answered individually are never merged
+    fn is_mergeable(&self) -> bool {
+        self.reply_to.is_none() && self.group.is_none() && self.device.request_id.is_none()
+    }
+
+    /// Apply the fields set in `request` on top of this one
+    fn merge(&mut self, request: CommandRequest) {
+        let device = &mut self.device;
+        let command = request.device;
+
+        device.power = command.power.or(device.power);
+        device.brightness = command.brightness.or(device.brightness);
+        device.color = command.color.or(device.color.take());
+        device.transition_ms = command.transition_ms.or(device.transition_ms);
+        device.refresh |= command.refresh;
+        if let Some(dps) = command.dps {
+            device.dps.get_or_insert_with(Default::default).extend(dps);
+        }
+    }
+}
+
+/// Queue of `/set` requests for a device. A request is merged field by field
+/// into the last pending one, so quick successive partial updates (e.g.
+/// power, then brightness) all reach the device, but a device that was
+/// offline doesn't replay every step of a slider once it's back. Shared so
+/// that the device task can pick up where it left off after reconnecting.
+#[derive(Default)]
+pub struct CommandQueue {
+    pending: std::sync::Mutex<VecDeque<CommandRequest>>,
+    notify: Notify,
+}
+
+impl CommandQueue {
+    pub fn push(&self, request: CommandRequest) {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.back_mut() {
+                Some(last) if last.is_mergeable() && request.is_mergeable() => last.merge(request),
+                _ => {
+                    if pending.len() >= MAX_PENDING_COMMANDS {
+                        if let Some(dropped) = pending.pop_front() {
+                            eprintln!("Command queue full, dropping {:?}", dropped.device);
+                        }
+                    }
+                    pending.push_back(request);
+                }
+            }
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next request
+    pub async fn pop(&self) -> CommandRequest {
+        loop {
+            if let Some(request) = self.pending.lock().unwrap().pop_front() {
+                return request;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Where incoming messages are routed to, replaced when devices are reloaded
+#[derive(Clone, Default)]
+struct Routes {
+    tuya_config: TuyaConfig,
+    queues: HashMap<String, Arc<CommandQueue>>,
+    /// Maps each device's `/set`, `/set/raw` and `/get` topics to its device id
+    set_topics: HashMap<String, String>,
+    raw_set_topics: HashMap<String, String>,
+    get_topics: HashMap<String, String>,
+    /// Maps each group's `/set` topic to its id and topic
+    group_set_topics: HashMap<String, (String, String)>,
+}
+
+impl Routes {
+    /// Routes for `tuya_config`. Devices that are already known keep their
+    /// command queue, so that queued commands survive a reload.
+    fn new(mqtt_config: &MqttConfig, tuya_config: TuyaConfig, previous: &Routes) -> Self {
+        let mut routes = Routes::default();
+
+        for device in tuya_config.devices.values() {
+            let queue = previous.queues.get(&device.id).cloned().unwrap_or_default();
+            routes.queues.insert(device.id.clone(), queue);
+
+            let topic = device_topic(&mqtt_config.topic, device);
+            for (topics, suffix) in [
+                (&mut routes.set_topics, "set"),
+                (&mut routes.raw_set_topics, "set/raw"),
+                (&mut routes.get_topics, "get"),
+            ] {
+                topics.insert(format!("{}/{}", topic, suffix), device.id.clone());
+            }
+        }
+
+        for (group_id, group) in &tuya_config.groups {
+            let topic = group_topic(&mqtt_config.topic, group_id, group);
+            routes
+                .group_set_topics
+                .insert(format!("{}/set", topic), (group_id.clone(), topic));
+        }
+
+        routes.tuya_config = tuya_config;
+        routes
+    }
+}
+
+/// Topics of devices and groups that aren't covered by the wildcard
+/// subscriptions on the base topic
+fn custom_subscriptions(mqtt_config: &MqttConfig, tuya_config: &TuyaConfig) -> Vec<String> {
+    let mut topics = vec![];
+
+    if let Some(homie_config) = &mqtt_config.homie {
+        for device in tuya_config.devices.values() {
+            topics.push(homie::set_subscription(homie_config, device));
+        }
+    }
+
+    let group_topics = tuya_config
+        .groups
+        .values()
+        .filter_map(|group| group.topic.as_ref());
+    for topic in group_topics {
+        topics.push(format!("{}/set", topic));
+    }
+
+    let device_topics = tuya_config
+        .devices
+        .values()
+        .filter_map(|device| device.topic.as_ref());
+    for topic in device_topics {
+        topics.push(format!("{}/set", topic));
+        topics.push(format!("{}/set/raw", topic));
+        topics.push(format!("{}/get", topic));
+    }
+
+    topics
+}
+
+async fn subscribe_all(client: &BrokerClient, topics: &[String], qos: QoS) {
+    for topic in topics {
+        let res = client.subscribe(topic, qos).await;
+
+        if let Err(e) = res {
+            eprintln!("Could not subscribe to topic {}: {:?}", topic, e);
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct MqttClient {
-    pub client: AsyncClient,
-    pub rx_map: HashMap<String, Receiver<Option<MqttDevice>>>,
+    pub client: BrokerClient,
+    routes: Arc<std::sync::RwLock<Routes>>,
     pub topic: String,
+    pub homie: Option<HomieConfig>,
+    pub publish_qos: QoS,
+    /// Retain published device state
+    pub retain: bool,
+    pub deadband: f32,
+    pub full_refresh_interval: Option<Duration>,
+    pub optimistic: bool,
+    pub groups: Arc<Groups>,
+    pub states: Arc<StateCache>,
+    /// Set once the event loop has sent a disconnect and stopped
+    pub disconnected: watch::Receiver<bool>,
+}
+
+impl MqttClient {
+    /// Command queue of a device
+    pub fn command_queue(&self, device_id: &str) -> Option<Arc<CommandQueue>> {
+        let routes = self.routes.read().unwrap();
+        routes.queues.get(device_id).cloned()
+    }
+
+    /// Route messages to `devices` from now on, subscribing to and
+    /// unsubscribing from custom topics as needed. Groups and scenes keep
+    /// the config they were started with.
+    pub async fn reload(
+        &self,
+        mqtt_config: &MqttConfig,
+        devices: HashMap<String, TuyaDeviceConfig>,
+    ) {
+        let (old_config, new_config) = {
+            let mut routes = self.routes.write().unwrap();
+            let tuya_config = TuyaConfig {
+                devices,
+                ..routes.tuya_config.clone()
+            };
+            let new_routes = Routes::new(mqtt_config, tuya_config, &routes);
+            let old_routes = std::mem::replace(&mut *routes, new_routes);
+            (old_routes.tuya_config, routes.tuya_config.clone())
+        };
+
+        let old_topics = custom_subscriptions(mqtt_config, &old_config);
+        let new_topics = custom_subscriptions(mqtt_config, &new_config);
+
+        let removed_topics = old_topics
+            .iter()
+            .filter(|topic| !new_topics.contains(topic));
+        for topic in removed_topics {
+            if let Err(e) = self.client.unsubscribe(topic).await {
+                eprintln!("Could not unsubscribe from topic {}: {:?}", topic, e);
+            }
+        }
+
+        let added_topics: Vec<_> = new_topics
+            .into_iter()
+            .filter(|topic| !old_topics.contains(topic))
+            .collect();
+        subscribe_all(&self.client, &added_topics, mqtt_config.subscribe_qos()).await;
+
+        if let Some(discovery_config) = &mqtt_config.discovery {
+            discovery::publish_discovery(&self.client, discovery_config, mqtt_config, &new_config)
+                .await;
+
+            // Subscribing again redelivers retained entries, so that entries
+            // of removed devices are cleaned up
+            let topic = discovery::discovery_subscription(discovery_config, mqtt_config);
+            subscribe_all(&self.client, &[topic], mqtt_config.subscribe_qos()).await;
+        }
+
+        if let Some(homie_config) = &mqtt_config.homie {
+            homie::publish_metadata(&self.client, self.publish_qos, homie_config, &new_config)
+                .await;
+        }
+    }
+
+    /// Publish offline bridge availability and disconnect cleanly, the broker
+    /// doesn't publish the last will in that case
+    pub async fn disconnect(&self, mqtt_config: &MqttConfig) -> Result<()> {
+        self.client
+            .publish(
+                mqtt_config.availability_topic(),
+                mqtt_config.publish_qos(),
+                true,
+                AVAILABILITY_OFFLINE,
+            )
+            .await?;
+        self.client.disconnect().await?;
+
+        let mut disconnected = self.disconnected.clone();
+        disconnected.wait_for(|disconnected| *disconnected).await?;
+
+        Ok(())
+    }
+}
+
+/// Read credentials and TLS settings from config
+#[allow(clippy::type_complexity)]
+fn configure_security(
+    mqtt_config: &MqttConfig,
+) -> Result<(Option<(String, String)>, Option<Transport>)> {
+    let password = match (&mqtt_config.password, &mqtt_config.password_file) {
+        (Some(password), _) => Some(password.clone()),
+        (None, Some(path)) => Some(
+            std::fs::read_to_string(path)
+                .context(format!("Could not read MQTT password file {}", path))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+        ),
+        (None, None) => None,
+    };
+
+    let credentials = match &mqtt_config.username {
+        Some(username) => Some((username.clone(), password.unwrap_or_default())),
+        None if password.is_some() => {
+            return Err(anyhow!("MQTT password is configured without a username"))
+        }
+        None => None,
+    };
+
+    let read = |path: &String| {
+        std::fs::read(path).context(format!("Could not read certificate file {}", path))
+    };
+
+    let client_auth = match (&mqtt_config.client_cert_file, &mqtt_config.client_key_file) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => {
+            return Err(anyhow!(
+            "Both client_cert_file and client_key_file are required for TLS client authentication"
+        ))
+        }
+    };
+
+    let tls = mqtt_config.tls.unwrap_or(mqtt_config.ca_file.is_some());
+
+    if !tls {
+        if client_auth.is_some() {
+            return Err(anyhow!("TLS client authentication requires tls = true"));
+        }
+        return Ok((credentials, None));
+    }
+
+    let transport = match &mqtt_config.ca_file {
+        Some(ca_file) => Transport::Tls(TlsConfiguration::Simple {
+            ca: read(ca_file)?,
+            alpn: None,
+            client_auth,
+        }),
+        None if client_auth.is_some() => {
+            return Err(anyhow!(
+                "TLS client authentication requires ca_file to be configured"
+            ))
+        }
+        None => Transport::tls_with_default_config(),
+    };
+
+    Ok((credentials, Some(transport)))
 }
 
 pub async fn init_mqtt(mqtt_config: &MqttConfig, tuya_config: &TuyaConfig) -> Result<MqttClient> {
-    let random_string: String = Alphanumeric.sample_string(&mut rand::rng(), 8);
+    let (credentials, transport) = configure_security(mqtt_config)?;
 
-    let mut options = MqttOptions::new(
-        format!("{}-{}", mqtt_config.id.clone(), random_string),
-        mqtt_config.host.clone(),
-        mqtt_config.port,
-    );
-    options.set_keep_alive(Duration::from_secs(5));
-    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let options = BrokerOptions {
+        client_id: mqtt_config.client_id(),
+        host: mqtt_config.host.clone(),
+        port: mqtt_config.port,
+        keep_alive: mqtt_config.keep_alive(),
+        clean_session: mqtt_config.clean_session.unwrap_or(true),
+        credentials,
+        transport,
+        last_will: Some((
+            mqtt_config.availability_topic(),
+            AVAILABILITY_OFFLINE.to_string(),
+            mqtt_config.publish_qos(),
+        )),
+    };
+    let (client, mut eventloop) = BrokerClient::new(mqtt_config.protocol, options, 10);
 
-    let mut tx_map = HashMap::new();
-    let mut rx_map = HashMap::new();
+    let data_dir = PathBuf::from(tuya_config.data_dir.as_deref().unwrap_or("."));
+    std::fs::create_dir_all(&data_dir)
+        .with_context(|| format!("Could not create data dir {}", data_dir.display()))?;
+
+    let states = Arc::new(StateCache::load(data_dir.join(state::STATE_FILE)));
+    let scenes = Arc::new(Scenes::load(
+        tuya_config.scenes.clone(),
+        match &tuya_config.scenes_file {
+            Some(scenes_file) => scenes_file.into(),
+            None => data_dir.join(scene::DEFAULT_SCENES_FILE),
+        },
+    ));
+
+    {
+        let states = states.clone();
+
+        task::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(state::SAVE_INTERVAL_SECS));
 
-    for device in tuya_config.devices.values() {
-        let (tx, rx) = tokio::sync::watch::channel(None);
-        let tx = Arc::new(RwLock::new(tx));
-        tx_map.insert(device.id.clone(), tx);
-        rx_map.insert(device.id.clone(), rx);
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = states.save() {
+                    eprintln!("Could not save state cache: {:?}", e);
+                }
+            }
+        });
     }
 
+    let routes = Arc::new(std::sync::RwLock::new(Routes::new(
+        mqtt_config,
+        tuya_config.clone(),
+        &Routes::default(),
+    )));
+
+    let (disconnected_tx, disconnected) = watch::channel(false);
+
     {
         let client = client.clone();
         let mqtt_config = mqtt_config.clone();
-        let tuya_config = tuya_config.clone();
+        let routes = routes.clone();
+        let states = states.clone();
 
         task::spawn(async move {
             loop {
                 let notification = eventloop.poll().await;
-                let mqtt_tx = tx_map.clone();
+
+                // Stop instead of reconnecting after a requested disconnect
+                if matches!(notification, Ok(BrokerEvent::Disconnected)) {
+                    let _ = disconnected_tx.send(true);
+                    break;
+                }
+
+                let Routes {
+                    tuya_config,
+                    queues,
+                    set_topics,
+                    raw_set_topics,
+                    get_topics,
+                    group_set_topics,
+                    ..
+                } = routes.read().unwrap().clone();
                 let client = client.clone();
                 let mqtt_config = mqtt_config.clone();
-                let tuya_config = tuya_config.clone();
+                let states = states.clone();
+                let scenes = scenes.clone();
 
                 let res = (|| async move {
+                    let subscribe_qos = mqtt_config.subscribe_qos();
+                    let publish_qos = mqtt_config.publish_qos();
+
                     match notification? {
-                        rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => {
-                            client
-                                .subscribe(format!("{}/set", mqtt_config.topic), QoS::AtMostOnce)
-                                .await?;
+                        BrokerEvent::Connected => {
+                            // The broker publishes our last will if we disappear,
+                            // so availability needs to be refreshed on every connect
+                            client.try_publish(
+                                mqtt_config.availability_topic(),
+                                publish_qos,
+                                true,
+                                AVAILABILITY_ONLINE,
+                            )?;
+
+                            for topic in [
+                                format!("{}/set", mqtt_config.topic),
+                                format!("{}/set/raw", mqtt_config.topic),
+                                format!("{}/get", mqtt_config.topic),
+                                mqtt_config.refresh_topic(),
+                                scene::scene_subscription(&mqtt_config.bridge_topic()),
+                            ] {
+                                client.subscribe(topic, subscribe_qos).await?;
+                            }
 
                             // Subscribe to custom topics asynchronously to avoid blocking the event loop
                             task::spawn(async move {
-                                for device in tuya_config.devices.values() {
-                                    if let Some(topic) = &device.topic {
-                                        let res = client
-                                            .subscribe(format!("{}/set", topic), QoS::AtMostOnce)
-                                            .await;
+                                if let Some(discovery_config) = &mqtt_config.discovery {
+                                    discovery::publish_discovery(
+                                        &client,
+                                        discovery_config,
+                                        &mqtt_config,
+                                        &tuya_config,
+                                    )
+                                    .await;
 
-                                        if let Err(e) = res {
-                                            eprintln!(
-                                                "Could not subscribe to topic {}: {:?}",
-                                                topic, e
-                                            );
-                                        }
+                                    // Retained entries from earlier runs are
+                                    // delivered on subscribe, which lets us
+                                    // remove ones for deleted devices
+                                    let topic = discovery::discovery_subscription(
+                                        discovery_config,
+                                        &mqtt_config,
+                                    );
+                                    let res = client.subscribe(&topic, subscribe_qos).await;
+
+                                    if let Err(e) = res {
+                                        eprintln!(
+                                            "Could not subscribe to topic {}: {:?}",
+                                            topic, e
+                                        );
                                     }
                                 }
+
+                                if let Some(homie_config) = &mqtt_config.homie {
+                                    homie::publish_metadata(
+                                        &client,
+                                        publish_qos,
+                                        homie_config,
+                                        &tuya_config,
+                                    )
+                                    .await;
+                                }
+
+                                let topics = custom_subscriptions(&mqtt_config, &tuya_config);
+                                subscribe_all(&client, &topics, subscribe_qos).await;
                             });
                         }
-                        rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) => {
-                            let device: MqttDevice = serde_json::from_slice(&msg.payload)?;
+                        BrokerEvent::Message(msg) => {
+                            if let Some(discovery_config) = &mqtt_config.discovery {
+                                if discovery::is_stale_discovery_topic(
+                                    &msg.topic,
+                                    discovery_config,
+                                    &mqtt_config,
+                                    &tuya_config,
+                                ) {
+                                    if !msg.payload.is_empty() {
+                                        task::spawn(async move {
+                                            discovery::remove_discovery(
+                                                &client,
+                                                publish_qos,
+                                                &msg.topic,
+                                            )
+                                            .await;
+                                        });
+                                    }
+                                    return Ok(());
+                                }
 
-                            let device_id = &device.id;
-                            let tx = mqtt_tx.get(device_id).context(format!(
+                                if msg.topic.starts_with(&discovery_config.prefix) {
+                                    return Ok(());
+                                }
+                            }
+
+                            let refresh_request = || CommandRequest {
+                                device: MqttDevice {
+                                    refresh: true,
+                                    ..Default::default()
+                                },
+                                reply_to: None,
+                                group: None,
+                            };
+
+                            if msg.topic == mqtt_config.refresh_topic() {
+                                for queue in queues.values() {
+                                    queue.push(refresh_request());
+                                }
+                                return Ok(());
+                            }
+
+                            if let Some(device_id) = get_topics.get(&msg.topic) {
+                                let queue = queues.get(device_id).context(format!(
+                                    "Could not find configured MQTT device with id {}",
+                                    device_id
+                                ))?;
+                                queue.push(refresh_request());
+                                return Ok(());
+                            }
+
+                            if let Some(device_id) = raw_set_topics.get(&msg.topic) {
+                                let queue = queues.get(device_id).context(format!(
+                                    "Could not find configured MQTT device with id {}",
+                                    device_id
+                                ))?;
+                                let device = MqttDevice {
+                                    id: Some(device_id.clone()),
+                                    dps: Some(serde_json::from_slice(&msg.payload)?),
+                                    ..Default::default()
+                                };
+                                queue.push(CommandRequest {
+                                    device,
+                                    reply_to: msg.reply_to,
+                                    group: None,
+                                });
+                                return Ok(());
+                            }
+
+                            let bridge_topic = mqtt_config.bridge_topic();
+                            match scene::parse_scene_topic(&bridge_topic, &msg.topic) {
+                                Some((name, SceneAction::Activate)) => {
+                                    let scene = scenes
+                                        .get(name)
+                                        .with_context(|| format!("Unknown scene {}", name))?;
+                                    // Captured scenes may refer to devices that
+                                    // have since been removed from the config
+                                    let devices: Vec<_> = scene
+                                        .devices
+                                        .into_iter()
+                                        .filter(|(device_id, _)| queues.contains_key(device_id))
+                                        .collect();
+                                    let members = devices.iter().map(|(id, _)| id.clone()).collect();
+
+                                    let group_request = group::start_group_request(
+                                        client.clone(),
+                                        publish_qos,
+                                        name.to_string(),
+                                        format!("{}/scene/{}", bridge_topic, name),
+                                        members,
+                                        vec![],
+                                        None,
+                                        msg.reply_to,
+                                    );
+
+                                    for (device_id, device) in devices {
+                                        queues[&device_id].push(CommandRequest {
+                                            device: MqttDevice {
+                                                id: Some(device_id.clone()),
+                                                ..device
+                                            },
+                                            reply_to: None,
+                                            group: Some(group_request.clone()),
+                                        });
+                                    }
+                                    return Ok(());
+                                }
+                                Some((name, SceneAction::Capture)) => {
+                                    // Payload is an optional list of device ids
+                                    let device_ids: Vec<String> = if msg.payload.is_empty() {
+                                        vec![]
+                                    } else {
+                                        serde_json::from_slice(&msg.payload)?
+                                    };
+                                    scenes.capture(name, &device_ids, &states);
+                                    return Ok(());
+                                }
+                                None => {}
+                            }
+
+                            if let Some((group_id, topic)) = group_set_topics.get(&msg.topic) {
+                                let device: MqttDevice = serde_json::from_slice(&msg.payload)?;
+
+                                // Look up every member first, so that the group
+                                // isn't left half switched. Members that are no
+                                // longer configured are reported as failed.
+                                let (members, missing): (Vec<_>, Vec<_>) = tuya_config.groups
+                                    [group_id]
+                                    .members
+                                    .iter()
+                                    .cloned()
+                                    .partition(|member| queues.contains_key(member));
+
+                                // Members send their commands simultaneously,
+                                // the group reports a combined result
+                                let group_request = group::start_group_request(
+                                    client.clone(),
+                                    publish_qos,
+                                    group_id.clone(),
+                                    topic.clone(),
+                                    members.clone(),
+                                    missing,
+                                    device.request_id.clone(),
+                                    msg.reply_to,
+                                );
+
+                                for member in &members {
+                                    queues[member].push(CommandRequest {
+                                        device: MqttDevice {
+                                            id: Some(member.clone()),
+                                            ..device.clone()
+                                        },
+                                        reply_to: None,
+                                        group: Some(group_request.clone()),
+                                    });
+                                }
+                                return Ok(());
+                            }
+
+                            if let Some(homie_config) = &mqtt_config.homie {
+                                let update = homie::parse_set_message(
+                                    homie_config,
+                                    &tuya_config,
+                                    &msg.topic,
+                                    &msg.payload,
+                                );
+
+                                if let Some(update) = update {
+                                    let (device_id, device) = update?;
+                                    let queue = queues.get(&device_id).context(format!(
+                                        "Could not find configured MQTT device with id {}",
+                                        device_id
+                                    ))?;
+                                    queue.push(CommandRequest {
+                                        device,
+                                        reply_to: msg.reply_to,
+                                        group: None,
+                                    });
+                                    return Ok(());
+                                }
+                            }
+
+                            let device_id = set_topics.get(&msg.topic).context(format!(
+                                "Could not find configured MQTT device with topic {}",
+                                msg.topic
+                            ))?;
+
+                            let mut device: MqttDevice = serde_json::from_slice(&msg.payload)?;
+
+                            match &device.id {
+                                Some(id) if id != device_id => {
+                                    eprintln!(
+                                        "Ignoring message on {}: payload id {} does not match device id {}",
+                                        msg.topic, id, device_id
+                                    );
+                                    return Ok(());
+                                }
+                                _ => device.id = Some(device_id.clone()),
+                            }
+
+                            let queue = queues.get(device_id).context(format!(
                                 "Could not find configured MQTT device with id {}",
                                 device_id
                             ))?;
-                            let tx = tx.write().await;
-                            tx.send(Some(device))?;
+                            queue.push(CommandRequest {
+                                device,
+                                reply_to: msg.reply_to,
+                                group: None,
+                            });
                         }
-                        _ => {}
+                        BrokerEvent::Disconnected | BrokerEvent::Other => {}
                     }
                     Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
                 })()
@@ -160,7 +823,78 @@ pub async fn init_mqtt(mqtt_config: &MqttConfig, tuya_config: &TuyaConfig) -> Re
 
     Ok(MqttClient {
         client,
-        rx_map,
+        routes,
         topic: mqtt_config.topic.clone(),
+        homie: mqtt_config.homie.clone(),
+        publish_qos: mqtt_config.publish_qos(),
+        retain: mqtt_config.retain.unwrap_or(true),
+        deadband: mqtt_config.deadband.unwrap_or_default(),
+        full_refresh_interval: mqtt_config.full_refresh_interval.map(Duration::from_secs),
+        optimistic: mqtt_config.optimistic.unwrap_or(false),
+        groups: Arc::new(Groups::new(&mqtt_config.topic, tuya_config.groups.clone())),
+        states,
+        disconnected,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(device: MqttDevice) -> CommandRequest {
+        CommandRequest {
+            device,
+            reply_to: None,
+            group: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_command_queue_merges_partial_updates() {
+        let queue = CommandQueue::default();
+        queue.push(request(MqttDevice {
+            power: Some(true),
+            ..Default::default()
+        }));
+        queue.push(request(MqttDevice {
+            brightness: Some(0.2),
+            ..Default::default()
+        }));
+        queue.push(request(MqttDevice {
+            brightness: Some(0.8),
+            dps: Some(serde_json::from_str(r#"{ "26": 10 }"#).unwrap()),
+            ..Default::default()
+        }));
+        // Answered individually, so kept apart
+        queue.push(request(MqttDevice {
+            power: Some(false),
+            request_id: Some("off".to_string()),
+            ..Default::default()
+        }));
+
+        let merged = queue.pop().await.device;
+        assert_eq!(merged.power, Some(true));
+        assert_eq!(merged.brightness, Some(0.8));
+        assert_eq!(merged.dps.unwrap()["26"], 10);
+
+        let off = queue.pop().await.device;
+        assert_eq!(off.request_id.as_deref(), Some("off"));
+        assert_eq!(off.brightness, None);
+        assert!(queue.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_command_queue_is_bounded() {
+        let queue = CommandQueue::default();
+        for n in 0..MAX_PENDING_COMMANDS + 5 {
+            queue.push(request(MqttDevice {
+                request_id: Some(n.to_string()),
+                ..Default::default()
+            }));
+        }
+
+        let pending = queue.pending.lock().unwrap();
+        assert_eq!(pending.len(), MAX_PENDING_COMMANDS);
+        assert_eq!(pending[0].device.request_id.as_deref(), Some("5"));
+    }
+}
@@ -0,0 +1,337 @@
+//! Running device tasks, and reloading devices when the config file changes
+//! (or on SIGHUP) without restarting the bridge.
+//!
+//! New devices are started and removed devices are stopped. Devices whose
+//! connection settings (ip, local_key or version) changed are restarted, other
+//! settings are applied to the running device without reconnecting.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use futures::future::join_all;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::config::MqttConfig;
+use crate::mqtt::MqttClient;
+use crate::tuya::{init_tuya, TuyaDeviceConfig};
+
+/// How long a device gets to finish queued commands and disconnect when it
+/// is stopped
+const DEVICE_STOP_TIMEOUT_MS: u64 = 10_000;
+
+/// How often the config file is checked for changes
+const CONFIG_POLL_INTERVAL_MS: u64 = 2_000;
+
+/// Editors may write the config file in several steps, wait for them to
+/// finish before reading it
+const CONFIG_SETTLE_MS: u64 = 500;
+
+/// Difference between the running devices and a reloaded config
+#[derive(Debug, Default, PartialEq)]
+pub struct DeviceChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Devices whose connection settings changed, these are restarted
+    pub restarted: Vec<String>,
+    /// Devices where only other settings changed, these keep their connection
+    pub updated: Vec<String>,
+}
+
+impl DeviceChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.restarted.is_empty()
+            && self.updated.is_empty()
+    }
+}
+
+/// Whether the device has to reconnect for `new` to take effect
+fn needs_restart(running: &TuyaDeviceConfig, new: &TuyaDeviceConfig) -> bool {
+    running.ip != new.ip || running.local_key != new.local_key || running.version != new.version
+}
+
+pub fn device_changes(
+    running: &HashMap<String, TuyaDeviceConfig>,
+    new: &HashMap<String, TuyaDeviceConfig>,
+) -> DeviceChanges {
+    let mut changes = DeviceChanges::default();
+
+    for (device_id, device) in new {
+        match running.get(device_id) {
+            None => changes.added.push(device_id.clone()),
+            Some(running) if needs_restart(running, device) => {
+                changes.restarted.push(device_id.clone())
+            }
+            Some(running) if running != device => changes.updated.push(device_id.clone()),
+            Some(_) => {}
+        }
+    }
+
+    changes.removed = running
+        .keys()
+        .filter(|device_id| !new.contains_key(*device_id))
+        .cloned()
+        .collect();
+
+    changes.added.sort();
+    changes.removed.sort();
+    changes.restarted.sort();
+    changes.updated.sort();
+    changes
+}
+
+struct DeviceTask {
+    /// Settings of the running device, updated on reload
+    config: watch::Sender<TuyaDeviceConfig>,
+    shutdown: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+/// Tasks of all running devices
+pub struct DeviceTasks {
+    mqtt_client: MqttClient,
+    tasks: HashMap<String, DeviceTask>,
+}
+
+impl DeviceTasks {
+    pub fn new(mqtt_client: MqttClient) -> Self {
+        Self {
+            mqtt_client,
+            tasks: HashMap::new(),
+        }
+    }
+
+    pub async fn start(&mut self, device: TuyaDeviceConfig) {
+        let device_id = device.id.clone();
+        let (config, config_rx) = watch::channel(device);
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let handle = init_tuya(config_rx, self.mqtt_client.clone(), shutdown_rx).await;
+
+        self.tasks.insert(
+            device_id,
+            DeviceTask {
+                config,
+                shutdown,
+                handle,
+            },
+        );
+    }
+
+    /// Stop the given devices, they finish queued commands, disconnect and
+    /// publish offline availability
+    pub async fn stop(&mut self, device_ids: &[String]) {
+        let tasks: Vec<_> = device_ids
+            .iter()
+            .filter_map(|device_id| self.tasks.remove(device_id))
+            .collect();
+
+        join_all(tasks.into_iter().map(|task| async move {
+            let _ = task.shutdown.send(true);
+
+            let abort_handle = task.handle.abort_handle();
+            let stop_timeout = Duration::from_millis(DEVICE_STOP_TIMEOUT_MS);
+            if timeout(stop_timeout, task.handle).await.is_err() {
+                eprintln!(
+                    "Timed out waiting for {} to disconnect",
+                    task.config.borrow().name
+                );
+                abort_handle.abort();
+            }
+        }))
+        .await;
+    }
+
+    /// Stop all devices
+    pub async fn stop_all(&mut self) {
+        let device_ids: Vec<_> = self.tasks.keys().cloned().collect();
+        self.stop(&device_ids).await;
+    }
+
+    /// Start, stop, restart and update devices to match `devices`
+    pub async fn reload(
+        &mut self,
+        mqtt_config: &MqttConfig,
+        mut devices: HashMap<String, TuyaDeviceConfig>,
+    ) {
+        let running = self
+            .tasks
+            .iter()
+            .map(|(device_id, task)| (device_id.clone(), task.config.borrow().clone()))
+            .collect();
+        let changes = device_changes(&running, &devices);
+
+        if changes.is_empty() {
+            println!("Config reloaded, no device changes");
+            return;
+        }
+
+        // Stopped together, so that signals wait for at most one stop timeout
+        let stopped: Vec<_> = changes
+            .removed
+            .iter()
+            .chain(&changes.restarted)
+            .cloned()
+            .collect();
+        self.stop(&stopped).await;
+
+        // Commands for removed devices are no longer accepted from here on
+        self.mqtt_client.reload(mqtt_config, devices.clone()).await;
+
+        for device_id in changes.added.iter().chain(&changes.restarted) {
+            if let Some(device) = devices.remove(device_id) {
+                self.start(device).await;
+            }
+        }
+
+        // Discovery and subscriptions were updated above, the device picks up
+        // its new topics and settings without reconnecting
+        for device_id in &changes.updated {
+            if let (Some(task), Some(device)) =
+                (self.tasks.get(device_id), devices.remove(device_id))
+            {
+                task.config.send_replace(device);
+            }
+        }
+
+        println!(
+            "Config reloaded: added {:?}, removed {:?}, restarted {:?}, updated {:?}",
+            changes.added, changes.removed, changes.restarted, changes.updated
+        );
+    }
+}
+
+/// Notices changes to the config file (and devices file or directory), and
+/// SIGHUP
+pub struct ConfigWatcher {
+    paths: Vec<PathBuf>,
+    modified: Vec<Option<SystemTime>>,
+    #[cfg(unix)]
+    sighup: tokio::signal::unix::Signal,
+}
+
+impl ConfigWatcher {
+    pub fn new(paths: Vec<PathBuf>) -> std::io::Result<Self> {
+        Ok(Self {
+            modified: modified_all(&paths),
+            paths,
+            #[cfg(unix)]
+            sighup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    /// Wait until the config file changed or SIGHUP is received
+    pub async fn changed(&mut self) {
+        let mut interval = tokio::time::interval(Duration::from_millis(CONFIG_POLL_INTERVAL_MS));
+
+        loop {
+            tokio::select! {
+                _ = self.hangup() => break,
+                _ = interval.tick() => {
+                    if modified_all(&self.paths) != self.modified {
+                        tokio::time::sleep(Duration::from_millis(CONFIG_SETTLE_MS)).await;
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Changes made while settling are part of this reload
+        self.modified = modified_all(&self.paths);
+    }
+
+    #[cfg(unix)]
+    async fn hangup(&mut self) {
+        self.sighup.recv().await;
+    }
+
+    #[cfg(not(unix))]
+    async fn hangup(&mut self) {
+        std::future::pending().await
+    }
+}
+
+fn modified_all(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths.iter().map(|path| modified(path)).collect()
+}
+
+/// Last modification of a file, or of a directory and any file in it
+fn modified(path: &Path) -> Option<SystemTime> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return modified;
+    };
+
+    entries
+        .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+        .chain(modified)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: &str, ip: &str) -> (String, TuyaDeviceConfig) {
+        (
+            id.to_string(),
+            TuyaDeviceConfig {
+                id: id.to_string(),
+                ip: ip.to_string(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_device_changes() {
+        let running = HashMap::from([
+            device("a", "192.168.1.10"),
+            device("b", "192.168.1.11"),
+            device("c", "192.168.1.12"),
+        ]);
+        let mut new = HashMap::from([
+            device("a", "192.168.1.10"),
+            device("b", "192.168.1.21"),
+            device("d", "192.168.1.13"),
+        ]);
+
+        assert_eq!(
+            device_changes(&running, &new),
+            DeviceChanges {
+                added: vec!["d".to_string()],
+                removed: vec!["c".to_string()],
+                restarted: vec!["b".to_string()],
+                updated: vec![],
+            }
+        );
+
+        // Renaming a device or moving its topic doesn't require reconnecting
+        let a = new.get_mut("a").unwrap();
+        a.name = "Kitchen".to_string();
+        a.topic = Some("kitchen".to_string());
+
+        assert_eq!(
+            device_changes(&running, &new),
+            DeviceChanges {
+                added: vec!["d".to_string()],
+                removed: vec!["c".to_string()],
+                restarted: vec!["b".to_string()],
+                updated: vec!["a".to_string()],
+            }
+        );
+
+        let b = new.get_mut("b").unwrap();
+        b.ip = "192.168.1.11".to_string();
+        b.local_key = "0123456789abcdef".to_string();
+        assert_eq!(
+            device_changes(&running, &new).restarted,
+            vec!["b".to_string()]
+        );
+        assert!(device_changes(&running, &running).is_empty());
+    }
+}
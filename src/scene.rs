@@ -0,0 +1,178 @@
+//! Bridge-side scenes
+//!
+//! A scene is a set of device states that is applied at once by publishing to
+//! `<bridge topic>/scene/<name>/activate`. Scenes are defined in the config,
+//! or captured from the current state of devices by publishing to
+//! `<bridge topic>/scene/<name>/capture`. Captured scenes are saved to
+//! `scenes_file` so that they survive restarts.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::mqtt::MqttDevice;
+use crate::state::{read_discardable, write_atomically, StateCache};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SceneConfig {
+    /// Device id -> state to apply, in the same format as `/set` messages
+    pub devices: HashMap<String, MqttDevice>,
+}
+
+pub const DEFAULT_SCENES_FILE: &str = "scenes.json";
+
+/// What to do with a scene, the last segment of a scene topic
+#[derive(Debug, PartialEq)]
+pub enum SceneAction {
+    Activate,
+    Capture,
+}
+
+/// Parse `<bridge topic>/scene/<name>/<action>` into scene name and action
+pub fn parse_scene_topic<'a>(bridge_topic: &str, topic: &'a str) -> Option<(&'a str, SceneAction)> {
+    let rest = topic.strip_prefix(&format!("{}/scene/", bridge_topic))?;
+
+    match rest.split('/').collect::<Vec<_>>()[..] {
+        [name, "activate"] => Some((name, SceneAction::Activate)),
+        [name, "capture"] => Some((name, SceneAction::Capture)),
+        _ => None,
+    }
+}
+
+/// Topic filter matching all scene topics
+pub fn scene_subscription(bridge_topic: &str) -> String {
+    format!("{}/scene/+/+", bridge_topic)
+}
+
+pub struct Scenes {
+    configured: HashMap<String, SceneConfig>,
+    captured: Mutex<HashMap<String, SceneConfig>>,
+    file: PathBuf,
+    /// Held while writing `file`, so that saves finishing out of order
+    /// don't overwrite newer scenes
+    saving: Mutex<()>,
+}
+
+impl Scenes {
+    /// Scenes from config plus previously captured scenes from `file`,
+    /// captured scenes take precedence (with a warning, as later edits to the
+    /// configured scene have no effect)
+    pub fn load(configured: HashMap<String, SceneConfig>, file: PathBuf) -> Self {
+        // Only configured scenes are available if `file` can't be read
+        let captured: HashMap<String, SceneConfig> =
+            read_discardable(&file, "captured scenes").unwrap_or_default();
+
+        let mut shadowed: Vec<_> = captured
+            .keys()
+            .filter(|name| configured.contains_key(*name))
+            .collect();
+        shadowed.sort();
+        for name in shadowed {
+            eprintln!(
+                "Scene {} in the config is overridden by the scene captured in {}",
+                name,
+                file.display()
+            );
+        }
+
+        Self {
+            configured,
+            captured: Mutex::new(captured),
+            file,
+            saving: Mutex::new(()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<SceneConfig> {
+        let captured = self.captured.lock().unwrap();
+        captured
+            .get(name)
+            .or_else(|| self.configured.get(name))
+            .cloned()
+    }
+
+    /// Snapshot the current state of `device_ids` (all devices with a known
+    /// state if empty) into a scene, and save it to disk in the background
+    pub fn capture(self: &Arc<Self>, name: &str, device_ids: &[String], states: &StateCache) {
+        let device_ids = if device_ids.is_empty() {
+            states.device_ids()
+        } else {
+            device_ids.to_vec()
+        };
+
+        let devices = device_ids
+            .into_iter()
+            .filter_map(|device_id| {
+                let state = states.get(&device_id)?;
+                let state = MqttDevice {
+                    power: state.power,
+                    brightness: state.brightness,
+                    color: state.color,
+                    ..Default::default()
+                };
+                Some((device_id, state))
+            })
+            .collect();
+
+        if self.configured.contains_key(name) {
+            eprintln!(
+                "Captured scene {} overrides the scene with that name in the config",
+                name
+            );
+        }
+
+        self.captured
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), SceneConfig { devices });
+
+        // Called from the MQTT event loop, which must not wait for the disk
+        let scenes = self.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = scenes.save() {
+                eprintln!("Could not save scenes: {:?}", e);
+            }
+        });
+    }
+
+    /// Write the captured scenes to `file`
+    fn save(&self) -> Result<()> {
+        let _saving = self.saving.lock().unwrap();
+
+        let contents = {
+            let captured = self.captured.lock().unwrap();
+            serde_json::to_string_pretty(&*captured)?
+        };
+        write_atomically(&self.file, contents)
+            .with_context(|| format!("Could not save scenes to {}", self.file.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scene_topic() {
+        let bridge_topic = "home/lights/tuya/bridge";
+
+        assert_eq!(
+            parse_scene_topic(bridge_topic, "home/lights/tuya/bridge/scene/movie/activate"),
+            Some(("movie", SceneAction::Activate))
+        );
+        assert_eq!(
+            parse_scene_topic(bridge_topic, "home/lights/tuya/bridge/scene/movie/capture"),
+            Some(("movie", SceneAction::Capture))
+        );
+        assert_eq!(
+            parse_scene_topic(bridge_topic, "home/lights/tuya/bridge/scene/movie"),
+            None
+        );
+        assert_eq!(
+            parse_scene_topic(bridge_topic, "home/lights/tuya/movie/activate"),
+            None
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Last known state of every device, persisted to `<data_dir>/state.json` so
+//! that it can be published right away after a restart.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::mqtt::MqttDevice;
+use crate::tuya::TuyaDeviceConfig;
+
+pub const STATE_FILE: &str = "state.json";
+
+/// How often the state cache is written to disk (if it changed)
+pub const SAVE_INTERVAL_SECS: u64 = 60;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PersistedDevice {
+    /// Last published state
+    pub state: Option<MqttDevice>,
+    pub last_connected: Option<DateTime<Utc>>,
+    /// Address and protocol version the device was last reached at
+    pub ip: Option<String>,
+    pub version: Option<String>,
+}
+
+#[derive(Default)]
+pub struct StateCache {
+    devices: Mutex<HashMap<String, PersistedDevice>>,
+    /// Where the cache is saved, not persisted if None
+    path: Option<PathBuf>,
+    dirty: AtomicBool,
+}
+
+/// Read a JSON file the bridge wrote itself. Such a file can be thrown away,
+/// so if it can't be read or parsed (e.g. it was truncated, or written by an
+/// older version) it is moved aside to `<path>.bad` and None is returned.
+pub fn read_discardable<T: DeserializeOwned>(path: &Path, what: &str) -> Option<T> {
+    if !path.exists() {
+        return None;
+    }
+
+    let res = std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(serde_json::from_str(&contents)?));
+
+    match res {
+        Ok(value) => Some(value),
+        Err(e) => {
+            let mut bad_path = path.as_os_str().to_owned();
+            bad_path.push(".bad");
+            eprintln!(
+                "Could not read {} {}, starting without it: {:?}",
+                what,
+                path.display(),
+                e
+            );
+            if let Err(e) = std::fs::rename(path, &bad_path) {
+                eprintln!("Could not move {} aside: {:?}", path.display(), e);
+            }
+            None
+        }
+    }
+}
+
+/// Write to a temporary file first and rename it over `path`, so that a crash
+/// halfway through doesn't leave a truncated file behind
+pub fn write_atomically(path: &Path, contents: String) -> Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    std::fs::write(&tmp_path, contents)
+        .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Could not write {}", path.display()))?;
+
+    Ok(())
+}
+
+impl StateCache {
+    /// Load the cache from `path`, starting empty if it doesn't exist yet or
+    /// can't be read
+    pub fn load(path: PathBuf) -> Self {
+        let devices = read_discardable(&path, "state cache").unwrap_or_default();
+
+        Self {
+            devices: Mutex::new(devices),
+            path: Some(path),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Write the cache to disk if it changed since the last save
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        // Cleared before serializing so that updates made while writing are
+        // saved next time
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let res = serde_json::to_string_pretty(&*self.devices.lock().unwrap())
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| write_atomically(path, contents));
+
+        // Retried on the next save
+        if res.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+
+        res.context("Could not save state cache")
+    }
+
+    pub fn update(&self, device_id: &str, state: &MqttDevice) {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.entry(device_id.to_string()).or_default();
+        device.state = Some(MqttDevice {
+            stale: None,
+            ..state.clone()
+        });
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn mark_connected(&self, device: &TuyaDeviceConfig) {
+        let mut devices = self.devices.lock().unwrap();
+        let persisted = devices.entry(device.id.clone()).or_default();
+        persisted.last_connected = Some(Utc::now());
+        persisted.ip = Some(device.ip.clone());
+        persisted.version = Some(device.version.clone());
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<MqttDevice> {
+        let devices = self.devices.lock().unwrap();
+        devices.get(device_id)?.state.clone()
+    }
+
+    /// Ids of all devices with a known state
+    pub fn device_ids(&self) -> Vec<String> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .filter(|(_, device)| device.state.is_some())
+            .map(|(device_id, _)| device_id.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let path =
+            std::env::temp_dir().join(format!("tuya-mqtt-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let cache = StateCache::load(path.clone());
+        assert_eq!(cache.get("a"), None);
+
+        let state = MqttDevice {
+            power: Some(true),
+            brightness: Some(0.5),
+            stale: Some(true),
+            ..Default::default()
+        };
+        cache.update("a", &state);
+        cache.save().unwrap();
+
+        let cache = StateCache::load(path.clone());
+        let loaded = cache.get("a").unwrap();
+        assert_eq!(loaded.brightness, Some(0.5));
+        assert_eq!(loaded.stale, None);
+        assert_eq!(cache.device_ids(), vec!["a".to_string()]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_corrupt_cache() {
+        let path =
+            std::env::temp_dir().join(format!("tuya-mqtt-corrupt-{}.json", std::process::id()));
+        let bad_path = path.with_extension("json.bad");
+        std::fs::write(&path, r#"{"a": {"state": {"power": tr"#).unwrap();
+
+        let cache = StateCache::load(path.clone());
+        assert!(cache.device_ids().is_empty());
+        assert!(!path.exists());
+        assert!(bad_path.exists());
+
+        std::fs::remove_file(&bad_path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_save_is_retried() {
+        let dir = std::env::temp_dir().join(format!("tuya-mqtt-retry-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = StateCache::load(dir.join(STATE_FILE));
+
+        cache.update("a", &MqttDevice::default());
+        assert!(cache.save().is_err());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        cache.save().unwrap();
+        assert_eq!(
+            StateCache::load(dir.join(STATE_FILE)).device_ids(),
+            vec!["a"]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,536 @@
+//! Devices imported from the `devices.json` written by tinytuya's wizard, see
+//! `Config::tinytuya_devices`
+//!
+//! The device type and DP fields are derived from the DP mapping tinytuya
+//! fetches from the Tuya cloud, falling back to the Tuya product category for
+//! entries without one. Entries the config validation would reject are
+//! skipped with a warning, so that one stale entry doesn't stop the bridge.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, net::IpAddr, path::Path, str::FromStr};
+
+use crate::{
+    config::{DeviceConfig, DeviceId},
+    mqtt::{Capabilities, MAX_SUPPORTED_CT, MIN_SUPPORTED_CT},
+    tuya::{
+        CoverConfig, DeviceType, DEFAULT_BRIGHTNESS_FIELD, DEFAULT_COLOR_FIELD,
+        DEFAULT_COLOR_TEMP_FIELD,
+    },
+    tuyapi::mesparse::TuyaVersion,
+};
+
+/// Protocol version used when tinytuya didn't detect one
+const DEFAULT_VERSION: &str = "3.3";
+
+const LIGHT_POWER_CODES: &[&str] = &["switch_led"];
+const LIGHT_BRIGHTNESS_CODES: &[&str] = &["bright_value_v2", "bright_value"];
+const LIGHT_COLOR_TEMP_CODES: &[&str] = &["temp_value_v2", "temp_value"];
+const LIGHT_COLOR_CODES: &[&str] = &["colour_data_v2", "colour_data"];
+const SWITCH_POWER_CODES: &[&str] = &["switch_1", "switch"];
+const COVER_CODES: &[&str] = &["control"];
+/// Power metering DPs that devices only update on request
+const METERING_CODES: &[&str] = &["cur_current", "cur_power", "cur_voltage"];
+/// Sensor readings, in order of preference
+const SENSOR_CODES: &[&str] = &[
+    "va_temperature",
+    "temp_current",
+    "va_humidity",
+    "humidity_value",
+    "doorcontact_state",
+    "pir",
+    "presence_state",
+    "watersensor_state",
+    "smoke_sensor_status",
+    "gas_sensor_status",
+    "co2_value",
+    "pm25_value",
+];
+
+const LIGHT_CATEGORIES: &[&str] = &["dj", "dd", "dc", "fwd", "xdd", "gyd", "tgq", "fsd"];
+const SWITCH_CATEGORIES: &[&str] = &["kg", "cz", "pc", "tdq", "tgkg"];
+
+#[derive(Deserialize, Debug)]
+struct TinytuyaDevice {
+    id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    key: String,
+    #[serde(default)]
+    ip: Option<String>,
+    /// `ver` in tinytuya's `snapshot.json`, a string or a number
+    #[serde(default, alias = "ver")]
+    version: Option<Value>,
+    #[serde(default)]
+    category: Option<String>,
+    /// Sub-devices are reached through a gateway, which isn't supported
+    #[serde(default)]
+    sub: bool,
+    /// DP id -> DP metadata
+    #[serde(default)]
+    mapping: HashMap<String, DpMapping>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DpMapping {
+    code: String,
+    /// "Boolean", "Integer", "Enum", ...
+    #[serde(rename = "type", default)]
+    dp_type: String,
+    /// e.g. `{"range": [...]}` for enums
+    #[serde(default)]
+    values: Value,
+}
+
+/// Devices imported from a tinytuya `devices.json`, with a description of
+/// every entry that was skipped or may need adjusting
+pub struct Import {
+    pub devices: HashMap<DeviceId, DeviceConfig>,
+    pub warnings: Vec<String>,
+}
+
+/// Read the devices of a tinytuya `devices.json` that aren't in `configured`
+pub fn read_devices(path: &Path, configured: &HashMap<DeviceId, DeviceConfig>) -> Result<Import> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read tinytuya devices from {}", path.display()))?;
+
+    import(&json, configured)
+        .with_context(|| format!("Failed to read tinytuya devices from {}", path.display()))
+}
+
+fn import(json: &str, configured: &HashMap<DeviceId, DeviceConfig>) -> Result<Import> {
+    let entries: Vec<TinytuyaDevice> = serde_json::from_str(json)?;
+
+    let mut import = Import {
+        devices: HashMap::new(),
+        warnings: vec![],
+    };
+
+    // Device that uses each IP address
+    let mut ips: HashMap<String, String> = configured
+        .iter()
+        .map(|(device_id, device)| (device.ip.clone(), device_id.clone()))
+        .collect();
+
+    for entry in entries {
+        if configured.contains_key(&entry.id) {
+            continue;
+        }
+
+        let label = format!("{} ({})", entry.name, entry.id);
+        let device = device_config(&entry, &mut import.warnings).and_then(|device| {
+            match ips.get(&device.ip) {
+                Some(other) => Err(format!("ip {} is also used by device {}", device.ip, other)),
+                None => Ok(device),
+            }
+        });
+
+        match device {
+            Ok(device) => {
+                ips.insert(device.ip.clone(), entry.id.clone());
+                import.devices.insert(entry.id, device);
+            }
+            Err(reason) => import
+                .warnings
+                .push(format!("Skipped {}: {}", label, reason)),
+        }
+    }
+
+    import.warnings.sort();
+    Ok(import)
+}
+
+/// DP id of the first of `codes` this device has
+fn find_dp<'a>(entry: &'a TinytuyaDevice, codes: &[&str]) -> Option<&'a str> {
+    codes.iter().find_map(|code| {
+        entry
+            .mapping
+            .iter()
+            .filter(|(_, dp)| dp.code == *code)
+            .map(|(dp_id, _)| dp_id.as_str())
+            .min_by_key(|dp_id| dp_id.parse::<u8>().unwrap_or(u8::MAX))
+    })
+}
+
+fn has_category(entry: &TinytuyaDevice, categories: &[&str]) -> bool {
+    entry
+        .category
+        .as_deref()
+        .is_some_and(|category| categories.contains(&category))
+}
+
+fn device_config(
+    entry: &TinytuyaDevice,
+    warnings: &mut Vec<String>,
+) -> Result<DeviceConfig, String> {
+    if entry.sub {
+        return Err("sub-devices behind a gateway are not supported".to_string());
+    }
+    if entry.key.is_empty() {
+        return Err("no local key".to_string());
+    }
+    if entry.key.len() != 16 {
+        return Err(format!(
+            "local key must be 16 characters long, not {}",
+            entry.key.len()
+        ));
+    }
+    let ip = match entry.ip.as_deref() {
+        Some(ip) if !ip.is_empty() => ip.to_string(),
+        _ => return Err("no IP address, run tinytuya's network scan first".to_string()),
+    };
+    if IpAddr::from_str(&ip).is_err() {
+        return Err(format!("ip {:?} is not a valid IP address", ip));
+    }
+    let version = match &entry.version {
+        Some(Value::String(version)) if !version.is_empty() => version.clone(),
+        Some(Value::Number(version)) => version.to_string(),
+        _ => DEFAULT_VERSION.to_string(),
+    };
+    if TuyaVersion::from_str(&version).is_err() {
+        return Err(format!("version {} is not supported", version));
+    }
+
+    let mut device = DeviceConfig {
+        name: if entry.name.is_empty() {
+            entry.id.clone()
+        } else {
+            entry.name.clone()
+        },
+        local_key: Some(entry.key.clone()),
+        local_key_file: None,
+        local_key_env: None,
+        ip,
+        version,
+        max_brightness: None,
+        power_on_field: None,
+        capabilities: None,
+        topic: None,
+        brightness_curve: None,
+        device_type: None,
+        sensor_field: None,
+        cover: None,
+        refresh_dps: None,
+        allowed_dps: None,
+        denied_dps: None,
+        power_on: None,
+    };
+
+    let label = format!("{} ({})", device.name, entry.id);
+
+    if let Some(control) = find_dp(entry, COVER_CODES) {
+        let mapping = &entry.mapping[control];
+        let range = |value: &str| {
+            mapping.values["range"]
+                .as_array()
+                .is_some_and(|range| range.contains(&json!(value)))
+        };
+
+        let cover = match mapping.dp_type.as_str() {
+            // Opened and closed like a switch is turned on and off
+            "Boolean" => {
+                device.power_on_field = Some(control.to_string());
+                CoverConfig {
+                    field: control.to_string(),
+                    open: json!(true),
+                    close: json!(false),
+                    stop: None,
+                }
+            }
+            "Enum" if range("open") && range("close") => CoverConfig {
+                field: control.to_string(),
+                open: json!("open"),
+                close: json!("close"),
+                stop: range("stop").then(|| json!("stop")),
+            },
+            _ => {
+                return Err(format!(
+                    "cover DP {} has no known open and close values, use raw DPs instead",
+                    control
+                ))
+            }
+        };
+        device.device_type = Some(DeviceType::Cover);
+        device.cover = Some(cover);
+    } else if find_dp(entry, LIGHT_POWER_CODES).is_some()
+        || find_dp(entry, LIGHT_BRIGHTNESS_CODES).is_some()
+    {
+        device.power_on_field = find_dp(entry, LIGHT_POWER_CODES).map(str::to_string);
+
+        match find_dp(entry, LIGHT_BRIGHTNESS_CODES) {
+            // Brightness, colour temperature and colour are always written
+            // to the DPs of the v2 layout
+            Some(brightness) if brightness != DEFAULT_BRIGHTNESS_FIELD => {
+                warnings.push(format!(
+                    "{} uses DP {} for brightness, imported as a switch so that only power is controlled",
+                    label, brightness
+                ));
+                device.device_type = Some(DeviceType::Switch);
+            }
+            _ => {
+                device.device_type = Some(DeviceType::Light);
+                device.capabilities = Some(Capabilities {
+                    hs: find_dp(entry, LIGHT_COLOR_CODES) == Some(DEFAULT_COLOR_FIELD),
+                    ct: (find_dp(entry, LIGHT_COLOR_TEMP_CODES) == Some(DEFAULT_COLOR_TEMP_FIELD))
+                        .then_some(MIN_SUPPORTED_CT..MAX_SUPPORTED_CT),
+                });
+            }
+        }
+    } else if let Some(power) = find_dp(entry, SWITCH_POWER_CODES) {
+        device.device_type = Some(DeviceType::Switch);
+        device.power_on_field = Some(power.to_string());
+
+        let mut metering: Vec<u8> = METERING_CODES
+            .iter()
+            .filter_map(|code| find_dp(entry, &[code])?.parse().ok())
+            .collect();
+        metering.sort();
+        if !metering.is_empty() {
+            device.refresh_dps = Some(metering);
+        }
+    } else if let Some(sensor) = find_dp(entry, SENSOR_CODES) {
+        device.device_type = Some(DeviceType::Sensor);
+        device.sensor_field = Some(sensor.to_string());
+    } else if has_category(entry, LIGHT_CATEGORIES) {
+        device.device_type = Some(DeviceType::Light);
+    } else if has_category(entry, SWITCH_CATEGORIES) {
+        device.device_type = Some(DeviceType::Switch);
+        device.power_on_field = Some("1".to_string());
+    } else {
+        return Err(format!(
+            "unknown device type (category {}), add it to the config file instead",
+            entry.category.as_deref().unwrap_or("unknown")
+        ));
+    }
+
+    // The default power DP is the one of lights, other devices use DP 1
+    if device.power_on_field.is_none() && device.device_type != Some(DeviceType::Light) {
+        device.power_on_field = Some("1".to_string());
+    }
+
+    Ok(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICES_JSON: &str = r#"[
+        {
+            "name": "Living room bulb",
+            "id": "bf0123456789abcdefgh",
+            "key": "0123456789abcdef",
+            "category": "dj",
+            "ip": "192.168.1.10",
+            "version": "3.3",
+            "mapping": {
+                "20": {"code": "switch_led", "type": "Boolean", "values": {}},
+                "21": {"code": "work_mode", "type": "Enum", "values": {"range": ["white", "colour"]}},
+                "22": {"code": "bright_value_v2", "type": "Integer", "values": {"min": 10, "max": 1000}},
+                "23": {"code": "temp_value_v2", "type": "Integer", "values": {"min": 0, "max": 1000}},
+                "24": {"code": "colour_data_v2", "type": "Json", "values": {}}
+            }
+        },
+        {
+            "name": "Dishwasher plug",
+            "id": "bf1123456789abcdefgh",
+            "key": "1123456789abcdef",
+            "category": "cz",
+            "ip": "192.168.1.11",
+            "ver": 3.4,
+            "mapping": {
+                "1": {"code": "switch_1", "type": "Boolean", "values": {}},
+                "18": {"code": "cur_current", "type": "Integer", "values": {}},
+                "19": {"code": "cur_power", "type": "Integer", "values": {}},
+                "20": {"code": "cur_voltage", "type": "Integer", "values": {}}
+            }
+        },
+        {
+            "name": "Bedroom blinds",
+            "id": "bf2123456789abcdefgh",
+            "key": "2123456789abcdef",
+            "category": "cl",
+            "ip": "192.168.1.12",
+            "version": "3.3",
+            "mapping": {
+                "1": {"code": "control", "type": "Enum", "values": {"range": ["open", "stop", "close"]}},
+                "2": {"code": "percent_control", "type": "Integer", "values": {}}
+            }
+        },
+        {
+            "name": "Garage door",
+            "id": "bf8123456789abcdefgh",
+            "key": "8123456789abcdef",
+            "category": "ckmkzq",
+            "ip": "192.168.1.18",
+            "version": "3.3",
+            "mapping": {
+                "1": {"code": "control", "type": "Boolean", "values": {}}
+            }
+        },
+        {
+            "name": "Hallway thermometer",
+            "id": "bf3123456789abcdefgh",
+            "key": "3123456789abcdef",
+            "category": "wsdcg",
+            "ip": "192.168.1.13",
+            "version": "3.3",
+            "mapping": {
+                "1": {"code": "va_temperature", "type": "Integer", "values": {}},
+                "2": {"code": "va_humidity", "type": "Integer", "values": {}}
+            }
+        },
+        {
+            "name": "Old strip",
+            "id": "bf4123456789abcdefgh",
+            "key": "4123456789abcdef",
+            "category": "dd",
+            "ip": "192.168.1.14",
+            "version": "3.1",
+            "mapping": {
+                "1": {"code": "switch_led", "type": "Boolean", "values": {}},
+                "3": {"code": "bright_value", "type": "Integer", "values": {}},
+                "5": {"code": "colour_data", "type": "String", "values": {}}
+            }
+        },
+        {
+            "name": "Zigbee door sensor",
+            "id": "bf5123456789abcdefgh",
+            "key": "5123456789abcdef",
+            "category": "mcs",
+            "sub": true
+        },
+        {
+            "name": "Not scanned",
+            "id": "bf6123456789abcdefgh",
+            "key": "6123456789abcdef",
+            "category": "kg",
+            "ip": ""
+        },
+        {
+            "name": "New plug",
+            "id": "bfb123456789abcdefgh",
+            "key": "b123456789abcdef",
+            "category": "cz",
+            "ip": "192.168.1.19",
+            "version": "3.5",
+            "mapping": {
+                "1": {"code": "switch_1", "type": "Boolean", "values": {}}
+            }
+        },
+        {
+            "name": "Short key",
+            "id": "bfc123456789abcdefgh",
+            "key": "c123456789",
+            "category": "kg",
+            "ip": "192.168.1.20"
+        },
+        {
+            "name": "Stale bulb",
+            "id": "bfd123456789abcdefgh",
+            "key": "d123456789abcdef",
+            "category": "dj",
+            "ip": "192.168.1.10"
+        },
+        {
+            "name": "Stale plug",
+            "id": "bfe123456789abcdefgh",
+            "key": "e123456789abcdef",
+            "category": "cz",
+            "ip": "192.168.1.90"
+        },
+        {
+            "name": "Configured light",
+            "id": "bff123456789abcdefgh",
+            "key": "f123456789abcdef",
+            "category": "dj",
+            "ip": "192.168.1.21"
+        },
+        {
+            "name": "Robot vacuum",
+            "id": "bf7123456789abcdefgh",
+            "key": "7123456789abcdef",
+            "category": "sd",
+            "ip": "192.168.1.17"
+        }
+    ]"#;
+
+    #[test]
+    fn test_import() {
+        // Defined in the config file, the config file takes precedence
+        let configured = HashMap::from([(
+            "bff123456789abcdefgh".to_string(),
+            serde_json::from_value(serde_json::json!({
+                "name": "Hallway light",
+                "local_key": "f123456789abcdef",
+                "ip": "192.168.1.90",
+                "version": "3.3",
+            }))
+            .unwrap(),
+        )]);
+        let import = import(DEVICES_JSON, &configured).unwrap();
+
+        let bulb = &import.devices["bf0123456789abcdefgh"];
+        assert_eq!(bulb.name, "Living room bulb");
+        assert_eq!(bulb.local_key.as_deref(), Some("0123456789abcdef"));
+        assert_eq!(bulb.ip, "192.168.1.10");
+        assert_eq!(bulb.device_type, Some(DeviceType::Light));
+        assert_eq!(bulb.power_on_field.as_deref(), Some("20"));
+        assert_eq!(
+            bulb.capabilities,
+            Some(Capabilities {
+                hs: true,
+                ct: Some(MIN_SUPPORTED_CT..MAX_SUPPORTED_CT),
+            })
+        );
+
+        let plug = &import.devices["bf1123456789abcdefgh"];
+        assert_eq!(plug.version, "3.4");
+        assert_eq!(plug.device_type, Some(DeviceType::Switch));
+        assert_eq!(plug.power_on_field.as_deref(), Some("1"));
+        assert_eq!(plug.refresh_dps, Some(vec![18, 19, 20]));
+
+        let garage = &import.devices["bf8123456789abcdefgh"];
+        assert_eq!(garage.device_type, Some(DeviceType::Cover));
+        assert_eq!(garage.power_on_field.as_deref(), Some("1"));
+        assert_eq!(
+            garage.cover,
+            Some(CoverConfig {
+                field: "1".to_string(),
+                open: json!(true),
+                close: json!(false),
+                stop: None,
+            })
+        );
+
+        let blinds = &import.devices["bf2123456789abcdefgh"];
+        assert_eq!(blinds.device_type, Some(DeviceType::Cover));
+        assert_eq!(blinds.cover, Some(CoverConfig::default()));
+
+        let thermometer = &import.devices["bf3123456789abcdefgh"];
+        assert_eq!(thermometer.device_type, Some(DeviceType::Sensor));
+        assert_eq!(thermometer.sensor_field.as_deref(), Some("1"));
+
+        let strip = &import.devices["bf4123456789abcdefgh"];
+        assert_eq!(strip.device_type, Some(DeviceType::Switch));
+        assert_eq!(strip.power_on_field.as_deref(), Some("1"));
+        assert_eq!(strip.capabilities, None);
+
+        assert_eq!(import.devices.len(), 6);
+        assert_eq!(
+            import.warnings,
+            vec![
+                "Old strip (bf4123456789abcdefgh) uses DP 3 for brightness, imported as a switch so that only power is controlled",
+                "Skipped New plug (bfb123456789abcdefgh): version 3.5 is not supported",
+                "Skipped Not scanned (bf6123456789abcdefgh): no IP address, run tinytuya's network scan first",
+                "Skipped Robot vacuum (bf7123456789abcdefgh): unknown device type (category sd), add it to the config file instead",
+                "Skipped Short key (bfc123456789abcdefgh): local key must be 16 characters long, not 10",
+                "Skipped Stale bulb (bfd123456789abcdefgh): ip 192.168.1.10 is also used by device bf0123456789abcdefgh",
+                "Skipped Stale plug (bfe123456789abcdefgh): ip 192.168.1.90 is also used by device bff123456789abcdefgh",
+                "Skipped Zigbee door sensor (bf5123456789abcdefgh): sub-devices behind a gateway are not supported",
+            ]
+        );
+    }
+}
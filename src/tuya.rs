@@ -1,34 +1,40 @@
 use crate::tuyapi::mesparse::CommandType;
 use crate::tuyapi::PayloadStruct;
-use crate::tuyapi::{tuyadevice::TuyaDevice, Payload};
+use crate::tuyapi::{log_secrets, redact, tuyadevice::TuyaDevice, Payload};
 use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
 use futures::future::select_all;
 use futures::future::FutureExt;
 use log::{debug, info, warn};
-use rumqttc::QoS;
-use serde::Deserialize;
-use serde_json::{json, Value};
-use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::{net::IpAddr, str::FromStr, time::Duration};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{broadcast, oneshot, watch, Mutex, RwLock};
+use tokio::task::JoinHandle;
 use tokio::time::{timeout, Instant};
 
+use crate::brightness::BrightnessCurve;
+use crate::group::{GroupConfig, SyncGate};
+use crate::homie::{self, HomieState};
 use crate::mqtt::Capabilities;
 use crate::mqtt::Ct;
 use crate::mqtt::DeviceColor;
 use crate::mqtt::Hs;
 use crate::mqtt::MAX_SUPPORTED_CT;
 use crate::mqtt::MIN_SUPPORTED_CT;
+use crate::mqtt::{device_availability_topic, device_result_topic, device_topic};
 use crate::mqtt::{MqttClient, MqttDevice};
+use crate::mqtt::{AVAILABILITY_OFFLINE, AVAILABILITY_ONLINE};
+use crate::scene::SceneConfig;
 
 const DEFAULT_POWER_ON_FIELD: &str = "20";
 const DEFAULT_MODE_FIELD: &str = "21";
-const DEFAULT_BRIGHTNESS_FIELD: &str = "22";
-const DEFAULT_COLOR_TEMP_FIELD: &str = "23";
-const DEFAULT_COLOR_FIELD: &str = "24";
+pub const DEFAULT_BRIGHTNESS_FIELD: &str = "22";
+pub const DEFAULT_COLOR_TEMP_FIELD: &str = "23";
+pub const DEFAULT_COLOR_FIELD: &str = "24";
 
 /// Polling interval for querying device status (in milliseconds)
 /// Community research shows aggressive polling (< 10s) can trigger resource
@@ -52,6 +58,9 @@ const HEARTBEAT_JITTER_MS: u64 = 5_000;
 /// Timeout for individual operations (connect, get, set)
 const OPERATION_TIMEOUT_MS: u64 = 5_000;
 
+/// Reports buffered for each command waiting to be acknowledged
+const REPORTS_CAPACITY: usize = 16;
+
 /// Timeout for initial connection establishment
 const CONNECT_TIMEOUT_MS: u64 = 9_000;
 
@@ -183,7 +192,10 @@ impl DeviceEventLog {
 
     /// Dump the timeline to stderr in a format suitable for analysis
     /// Uses condensed output to reduce verbosity - consecutive identical events are grouped
-    pub fn dump_timeline(&self, failure_reason: &str) {
+    /// `local_key` is redacted from the output
+    pub fn dump_timeline(&self, failure_reason: &str, local_key: &str) {
+        let redact = |text: String| redact_key(&text, local_key);
+
         let separator_eq = "=".repeat(80);
         let separator_dash = "-".repeat(80);
 
@@ -193,7 +205,7 @@ impl DeviceEventLog {
         eprintln!("Device Name: {}", self.device_name);
         eprintln!("Device ID: {}", self.device_id);
         eprintln!("Protocol Version: {}", self.device_version);
-        eprintln!("Failure Reason: {}", failure_reason);
+        eprintln!("Failure Reason: {}", redact(failure_reason.to_string()));
         eprintln!("Dump Time: {}", Utc::now().to_rfc3339());
         eprintln!("Total Events: {}", self.events.len());
         eprintln!("{}", separator_dash);
@@ -317,7 +329,7 @@ impl DeviceEventLog {
                     i,
                     relative_ms,
                     event.timestamp.format("%H:%M:%S%.3f"),
-                    event.event_type
+                    redact(event.event_type.to_string())
                 );
             }
         }
@@ -355,7 +367,9 @@ impl DeviceEventLog {
                 let delta = window[0].instant.saturating_sub(window[1].instant);
                 eprintln!(
                     "  {} -> {} : {}ms",
-                    window[1].event_type, window[0].event_type, delta
+                    redact(window[1].event_type.to_string()),
+                    redact(window[0].event_type.to_string()),
+                    delta
                 );
             }
         }
@@ -368,13 +382,35 @@ impl DeviceEventLog {
 // Command Queue for Throttling
 // ============================================================================
 
+/// Outcome of a user command, reported back to whoever sent it
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandResult {
+    /// The device acknowledged the new values
+    pub success: bool,
+    /// DPS payload that was sent to the device
+    pub dps: Value,
+    /// Time from dequeuing the command until it was acknowledged or failed
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+pub type CommandResponder = oneshot::Sender<CommandResult>;
+
 /// Commands that can be sent to the device
 #[derive(Debug)]
 pub enum DeviceCommand {
-    /// Send a set_values command with DPS payload
-    SetValues(serde_json::Value),
+    /// Send a set_values command with DPS payload, optionally reporting the
+    /// outcome to the sender
+    SetValues {
+        dps: serde_json::Value,
+        responder: Option<CommandResponder>,
+        /// Wait for the other members of a group command before sending
+        gate: Option<Arc<SyncGate>>,
+    },
     /// Send a status poll (get) request
     Poll,
+    /// Ask the device to refresh the given DPs
+    DpRefresh(Vec<u8>),
     /// Send a heartbeat
     Heartbeat,
 }
@@ -390,6 +426,8 @@ impl DeviceCommand {
 pub struct PriorityCommandQueue {
     /// User commands (highest priority)
     user_commands: VecDeque<DeviceCommand>,
+    /// Pending DpRefresh, requested DPs are merged
+    dp_refresh_pending: Option<Vec<u8>>,
     /// Poll pending flag (only one poll needed at a time)
     poll_pending: bool,
     /// Heartbeat pending flag (only one heartbeat needed at a time)
@@ -400,6 +438,7 @@ impl PriorityCommandQueue {
     pub fn new() -> Self {
         Self {
             user_commands: VecDeque::new(),
+            dp_refresh_pending: None,
             poll_pending: false,
             heartbeat_pending: false,
         }
@@ -408,13 +447,22 @@ impl PriorityCommandQueue {
     /// Push a command to the queue with deduplication
     pub fn push(&mut self, cmd: DeviceCommand) {
         match cmd {
-            DeviceCommand::SetValues(_) => {
+            DeviceCommand::SetValues { .. } => {
                 self.user_commands.push_back(cmd);
             }
             DeviceCommand::Poll => {
                 // Only keep one poll pending
                 self.poll_pending = true;
             }
+            DeviceCommand::DpRefresh(dps) => {
+                // Only keep one refresh pending
+                let pending = self.dp_refresh_pending.get_or_insert_with(Vec::new);
+                for dp in dps {
+                    if !pending.contains(&dp) {
+                        pending.push(dp);
+                    }
+                }
+            }
             DeviceCommand::Heartbeat => {
                 // Only keep one heartbeat pending
                 self.heartbeat_pending = true;
@@ -424,11 +472,15 @@ impl PriorityCommandQueue {
 
     /// Pop the highest priority command from the queue
     pub fn pop(&mut self) -> Option<DeviceCommand> {
-        // Priority order: user commands > poll > heartbeat
+        // Priority order: user commands > refresh > poll > heartbeat
         if let Some(cmd) = self.user_commands.pop_front() {
             return Some(cmd);
         }
 
+        if let Some(dps) = self.dp_refresh_pending.take() {
+            return Some(DeviceCommand::DpRefresh(dps));
+        }
+
         if self.poll_pending {
             self.poll_pending = false;
             return Some(DeviceCommand::Poll);
@@ -441,6 +493,26 @@ impl PriorityCommandQueue {
 
         None
     }
+
+    /// Pop the next user command, leaving refreshes, polls and heartbeats
+    pub fn pop_user_command(&mut self) -> Option<DeviceCommand> {
+        self.user_commands.pop_front()
+    }
+
+    pub fn has_user_commands(&self) -> bool {
+        !self.user_commands.is_empty()
+    }
+}
+
+/// Replace `local_key`, as is or hex encoded, in text that is written to the
+/// logs, unless logging secrets was explicitly enabled
+fn redact_key(text: &str, local_key: &str) -> String {
+    if local_key.is_empty() || log_secrets() {
+        return text.to_string();
+    }
+
+    text.replace(local_key, "<redacted>")
+        .replace(&hex::encode(local_key), "<redacted>")
 }
 
 /// Minimum time a device must be failing before we dump its timeline (in milliseconds)
@@ -463,9 +535,72 @@ pub struct DeviceState {
     pub failure_dumped: std::sync::atomic::AtomicBool,
     /// Device name for logging
     pub device_name: String,
+    /// Last availability published for the device, None until first published
+    pub online: std::sync::Mutex<Option<bool>>,
+    /// MQTT client and topics used for publishing availability
+    mqtt_client: MqttClient,
+    topics: std::sync::Mutex<AvailabilityTopics>,
+    /// DPs of every control response or status report received from the
+    /// device, used to detect when a command has been acknowledged
+    pub reports: broadcast::Sender<Map<String, Value>>,
+    /// Cumulative state requested by `/set` commands, restored after the
+    /// device lost power if configured
+    pub last_commanded: std::sync::Mutex<Option<MqttDevice>>,
+    /// Set when the device reconnects after being unreachable, cleared once
+    /// the first state report has been checked for a power cycle
+    pub power_on_check_pending: std::sync::atomic::AtomicBool,
+}
+
+/// What to do when a device comes back in its factory power-on state (on, full
+/// brightness, white) after being unreachable, e.g. after a wall switch cut
+/// its power
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerOnBehavior {
+    /// Leave the device as it is
+    #[default]
+    AsIs,
+    /// Reapply the state last requested with `/set` commands
+    Restore,
+    /// Apply a fixed state, in the same format as `/set` messages
+    Fixed(Box<MqttDevice>),
+}
+
+/// What kind of device this is, decides how the device is presented to
+/// consumers such as Home Assistant
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    #[default]
+    Light,
+    Switch,
+    Sensor,
+    Cover,
+}
+
+/// Control DP of a cover and the values that open, close and stop it
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CoverConfig {
+    pub field: String,
+    pub open: Value,
+    pub close: Value,
+    /// Not every cover can be stopped
+    pub stop: Option<Value>,
+}
+
+impl Default for CoverConfig {
+    /// The control DP of Tuya curtain modules
+    fn default() -> Self {
+        Self {
+            field: "1".to_string(),
+            open: json!("open"),
+            close: json!("close"),
+            stop: Some(json!("stop")),
+        }
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Default, PartialEq, Deserialize)]
 pub struct TuyaDeviceConfig {
     pub name: String,
     pub id: String,
@@ -476,11 +611,65 @@ pub struct TuyaDeviceConfig {
     pub power_on_field: Option<String>,
     pub capabilities: Option<Capabilities>,
     pub topic: Option<String>,
+    #[serde(default)]
+    pub brightness_curve: BrightnessCurve,
+    #[serde(default)]
+    pub device_type: DeviceType,
+    pub sensor_field: Option<String>,
+    /// How covers are opened, closed and stopped, `CoverConfig::default()` if
+    /// not set
+    pub cover: Option<CoverConfig>,
+    /// DPs to refresh with a DpRefresh request (e.g. power metering DPs that
+    /// devices only update on request), a regular poll is used if not set
+    pub refresh_dps: Option<Vec<u8>>,
+    /// DPs that may be set through raw DPS passthrough, all if not set
+    pub allowed_dps: Option<Vec<u8>>,
+    /// DPs that may never be set through raw DPS passthrough
+    pub denied_dps: Option<Vec<u8>>,
+    #[serde(default)]
+    pub power_on: PowerOnBehavior,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+impl std::fmt::Debug for TuyaDeviceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TuyaDeviceConfig")
+            .field("name", &self.name)
+            .field("id", &self.id)
+            .field("local_key", &redact(self.local_key.as_bytes()))
+            .field("ip", &self.ip)
+            .field("version", &self.version)
+            .field("max_brightness", &self.max_brightness)
+            .field("power_on_field", &self.power_on_field)
+            .field("capabilities", &self.capabilities)
+            .field("topic", &self.topic)
+            .field("brightness_curve", &self.brightness_curve)
+            .field("device_type", &self.device_type)
+            .field("sensor_field", &self.sensor_field)
+            .field("cover", &self.cover)
+            .field("refresh_dps", &self.refresh_dps)
+            .field("allowed_dps", &self.allowed_dps)
+            .field("denied_dps", &self.denied_dps)
+            .field("power_on", &self.power_on)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct TuyaConfig {
     pub devices: HashMap<String, TuyaDeviceConfig>,
+    #[serde(default)]
+    pub groups: HashMap<String, GroupConfig>,
+    #[serde(default)]
+    pub scenes: HashMap<String, SceneConfig>,
+    /// Where captured scenes are saved
+    pub scenes_file: Option<String>,
+    /// Where the state cache (and by default captured scenes) are saved
+    pub data_dir: Option<String>,
+    /// Devices file or directory, watched for changes like the config file
+    pub devices_path: Option<std::path::PathBuf>,
+    /// tinytuya `devices.json`, watched for changes like the config file
+    pub tinytuya_devices: Option<std::path::PathBuf>,
+    pub unsafe_log_secrets: bool,
 }
 
 type TuyaDps = serde_json::Value;
@@ -490,6 +679,21 @@ struct TuyaDpsPayload {
     dps: Option<TuyaDps>,
 }
 
+/// DPs reported in `messages`, later messages take precedence
+fn reported_dps(messages: &[crate::tuyapi::mesparse::Message]) -> Map<String, Value> {
+    messages
+        .iter()
+        .filter_map(|message| match &message.payload {
+            Payload::Struct(PayloadStruct {
+                dps: Some(Value::Object(dps)),
+                ..
+            }) => Some(dps.clone()),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
 pub fn tuya_to_mqtt(
     messages: Vec<crate::tuyapi::mesparse::Message>,
     config: &TuyaDeviceConfig,
@@ -570,7 +774,8 @@ pub fn tuya_to_mqtt(
             let brightness = brightness - 10;
 
             // Scale range to 0-1
-            Some(brightness as f32 / 990.0)
+            let output = brightness as f32 / 990.0;
+            Some(config.brightness_curve.to_perceived(output))
         }
         Some(Value::String(s)) if s == "colour" => {
             let value = dps.get(DEFAULT_COLOR_FIELD).context(
@@ -580,29 +785,151 @@ pub fn tuya_to_mqtt(
                 .as_str()
                 .context("Could not deserialize color as string")?;
 
-            Some(i32::from_str_radix(&color[8..12], 16)? as f32 / 1000.0)
+            let output = i32::from_str_radix(&color[8..12], 16)? as f32 / 1000.0;
+            Some(config.brightness_curve.to_perceived(output))
         }
         _ => None,
     };
 
+    let sensor_value = config
+        .sensor_field
+        .as_ref()
+        .and_then(|field| dps.get(field))
+        .map(|value| match value {
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        });
+
     let device = MqttDevice {
-        id: config.id.clone(),
+        id: Some(config.id.clone()),
         name: Some(config.name.clone()),
         power,
         brightness,
         color,
         transition_ms: Some(500.0),
-        sensor_value: None,
+        sensor_value,
         capabilities: Some(config.capabilities.clone().unwrap_or_default()),
         raw: Some(dps_value),
+        ..Default::default()
     };
 
     Ok(device)
 }
 
+/// Device state to be published
+#[derive(Debug)]
+enum StateUpdate {
+    /// State reported by the device
+    Reported(MqttDevice),
+    /// Expected state after a `/set` command, used in optimistic mode
+    Expected(MqttDevice),
+}
+
+/// Returns true if the device reports the state Tuya bulbs come up in after
+/// being powered on
+fn is_factory_power_on_state(state: &MqttDevice) -> bool {
+    state.power == Some(true)
+        && state
+            .brightness
+            .is_some_and(|brightness| brightness >= 0.99)
+        && !matches!(state.color, Some(DeviceColor::Hs(_)))
+}
+
+/// Expected state after applying `command` to `state`
+fn merge_command(state: &MqttDevice, command: MqttDevice) -> MqttDevice {
+    MqttDevice {
+        power: command.power.or(state.power),
+        brightness: command.brightness.or(state.brightness),
+        color: command.color.or_else(|| state.color.clone()),
+        transition_ms: command.transition_ms.or(state.transition_ms),
+        ..state.clone()
+    }
+}
+
+/// Returns true if `new` differs from `old` enough to be worth publishing.
+/// Float fields are compared with `deadband`, DPs in `raw` that are already
+/// represented by other fields are ignored.
+pub fn state_changed(
+    old: &MqttDevice,
+    new: &MqttDevice,
+    config: &TuyaDeviceConfig,
+    deadband: f32,
+) -> bool {
+    let float_changed = |old: Option<f32>, new: Option<f32>| match (old, new) {
+        (Some(old), Some(new)) => (old - new).abs() > deadband,
+        (old, new) => old.is_some() != new.is_some(),
+    };
+
+    let color_changed = match (&old.color, &new.color) {
+        (Some(DeviceColor::Hs(old)), Some(DeviceColor::Hs(new))) => {
+            old.h != new.h || float_changed(Some(old.s), Some(new.s))
+        }
+        (old, new) => old != new,
+    };
+
+    let mapped_fields = [
+        config
+            .power_on_field
+            .as_deref()
+            .unwrap_or(DEFAULT_POWER_ON_FIELD),
+        DEFAULT_MODE_FIELD,
+        DEFAULT_BRIGHTNESS_FIELD,
+        DEFAULT_COLOR_TEMP_FIELD,
+        DEFAULT_COLOR_FIELD,
+    ];
+    let unmapped_dps = |raw: &Option<Value>| {
+        let mut raw = raw.clone();
+        if let Some(Value::Object(dps)) = &mut raw {
+            dps.retain(|dp, _| !mapped_fields.contains(&dp.as_str()));
+        }
+        raw
+    };
+
+    old.id != new.id
+        || old.name != new.name
+        || old.power != new.power
+        || float_changed(old.brightness, new.brightness)
+        || color_changed
+        || old.transition_ms != new.transition_ms
+        || old.sensor_value != new.sensor_value
+        || old.capabilities != new.capabilities
+        || old.stale != new.stale
+        || unmapped_dps(&old.raw) != unmapped_dps(&new.raw)
+}
+
+/// Returns true if `dp` may be set through raw DPS passthrough
+fn is_writable_dp(dp: &str, device_config: &TuyaDeviceConfig) -> bool {
+    let Ok(dp) = dp.parse::<u8>() else {
+        return false;
+    };
+
+    let allowed = device_config
+        .allowed_dps
+        .as_ref()
+        .is_none_or(|allowed| allowed.contains(&dp));
+    let denied = device_config
+        .denied_dps
+        .as_ref()
+        .is_some_and(|denied| denied.contains(&dp));
+
+    allowed && !denied
+}
+
 pub fn mqtt_to_tuya(mqtt_device: MqttDevice, device_config: &TuyaDeviceConfig) -> TuyaDps {
     let mut dps = serde_json::Map::new();
 
+    // Raw DPs go first, so that the fields below take precedence
+    for (dp, value) in mqtt_device.dps.into_iter().flatten() {
+        if is_writable_dp(&dp, device_config) {
+            dps.insert(dp, value);
+        } else {
+            warn!(
+                "Ignoring DP {} for {}: not writable",
+                dp, device_config.name
+            );
+        }
+    }
+
     if let Some(power) = mqtt_device.power {
         dps.insert(
             device_config
@@ -615,8 +942,10 @@ pub fn mqtt_to_tuya(mqtt_device: MqttDevice, device_config: &TuyaDeviceConfig) -
     }
 
     if let Some(brightness) = mqtt_device.brightness {
+        let output = device_config.brightness_curve.to_output(brightness);
+
         // Brightness goes from 10 to 1000 ¯\_(ツ)_/¯
-        let tuya_brightness = f32::floor(brightness * 990.0) as u32 + 10;
+        let tuya_brightness = f32::floor(output * 990.0) as u32 + 10;
         dps.insert(DEFAULT_BRIGHTNESS_FIELD.to_string(), json!(tuya_brightness));
     }
 
@@ -632,7 +961,7 @@ pub fn mqtt_to_tuya(mqtt_device: MqttDevice, device_config: &TuyaDeviceConfig) -
         let value = {
             let brightness = mqtt_device.brightness.unwrap_or(1.0);
             let brightness = brightness.min(device_config.max_brightness.unwrap_or(1.0));
-            brightness * 1000.0
+            device_config.brightness_curve.to_output(brightness) * 1000.0
         };
 
         let tuya_color_string = format!(
@@ -657,24 +986,144 @@ pub fn mqtt_to_tuya(mqtt_device: MqttDevice, device_config: &TuyaDeviceConfig) -
     serde_json::Value::Object(dps)
 }
 
+/// Availability topic and Homie `$state` attribute topic (if Homie publishing
+/// is enabled) of a device
+#[derive(Clone, PartialEq)]
+struct AvailabilityTopics {
+    availability: String,
+    homie_state: Option<String>,
+}
+
+impl AvailabilityTopics {
+    fn new(mqtt_client: &MqttClient, device_config: &TuyaDeviceConfig) -> Self {
+        Self {
+            availability: device_availability_topic(&mqtt_client.topic, device_config),
+            homie_state: mqtt_client
+                .homie
+                .as_ref()
+                .map(|homie_config| homie::state_attribute_topic(homie_config, device_config)),
+        }
+    }
+}
+
 impl DeviceState {
-    pub fn new(device_name: String, device_id: String, device_version: String) -> Self {
+    pub fn new(device_config: &TuyaDeviceConfig, mqtt_client: MqttClient) -> Self {
         Self {
             last_command_time: AtomicU64::new(0),
             last_successful_connection: AtomicU64::new(0),
             event_log: Mutex::new(DeviceEventLog::new(
-                device_name.clone(),
-                device_id,
-                device_version,
+                device_config.name.clone(),
+                device_config.id.clone(),
+                device_config.version.clone(),
             )),
             start_instant: Instant::now(),
             failure_dumped: std::sync::atomic::AtomicBool::new(false),
-            device_name,
+            device_name: device_config.name.clone(),
+            online: std::sync::Mutex::new(None),
+            topics: std::sync::Mutex::new(AvailabilityTopics::new(&mqtt_client, device_config)),
+            mqtt_client,
+            reports: broadcast::channel(REPORTS_CAPACITY).0,
+            last_commanded: std::sync::Mutex::new(None),
+            power_on_check_pending: std::sync::atomic::AtomicBool::new(false),
         }
     }
 
+    /// Publish device availability if it changed since the last call
+    async fn set_online(&self, online: bool) {
+        {
+            let mut last = self.online.lock().unwrap();
+            if *last == Some(online) {
+                return;
+            }
+            *last = Some(online);
+        }
+
+        self.publish_online(online).await;
+    }
+
+    /// Mark that the device was stopped on purpose, publishes offline
+    /// availability and the Homie `disconnected` state
+    pub async fn mark_stopped(&self) {
+        *self.online.lock().unwrap() = Some(false);
+        self.publish_availability(false, HomieState::Disconnected)
+            .await;
+    }
+
+    /// Move availability to the topics of a reloaded device config
+    async fn set_config(&self, device_config: &TuyaDeviceConfig) {
+        let topics = AvailabilityTopics::new(&self.mqtt_client, device_config);
+        let moved = std::mem::replace(&mut *self.topics.lock().unwrap(), topics.clone()) != topics;
+
+        let online = *self.online.lock().unwrap();
+        if let (true, Some(online)) = (moved, online) {
+            self.publish_online(online).await;
+        }
+    }
+
+    async fn publish_online(&self, online: bool) {
+        let homie_state = if online {
+            HomieState::Ready
+        } else {
+            HomieState::Lost
+        };
+        self.publish_availability(online, homie_state).await;
+    }
+
+    async fn publish_availability(&self, online: bool, homie_state: HomieState) {
+        let topics = self.topics.lock().unwrap().clone();
+
+        let payload = if online {
+            AVAILABILITY_ONLINE
+        } else {
+            AVAILABILITY_OFFLINE
+        };
+
+        let res = self
+            .mqtt_client
+            .client
+            .publish(
+                &topics.availability,
+                self.mqtt_client.publish_qos,
+                true,
+                payload,
+            )
+            .await;
+
+        if let Err(e) = res {
+            warn!(
+                "Error publishing availability for {}: {:?}",
+                self.device_name, e
+            );
+        }
+
+        if let Some(topic) = &topics.homie_state {
+            let res = self
+                .mqtt_client
+                .client
+                .publish(
+                    topic,
+                    self.mqtt_client.publish_qos,
+                    true,
+                    homie_state.as_str(),
+                )
+                .await;
+
+            if let Err(e) = res {
+                warn!(
+                    "Error publishing Homie state for {}: {:?}",
+                    self.device_name, e
+                );
+            }
+        }
+    }
+
+    /// Mark that device is unreachable, publishes offline availability
+    pub async fn mark_disconnected(&self) {
+        self.set_online(false).await;
+    }
+
     /// Mark that device successfully connected - reset failure state and log recovery if needed
-    pub fn mark_connected(&self) {
+    pub async fn mark_connected(&self) {
         let now = self.elapsed_ms();
         let last_success = self.last_successful_connection.load(Ordering::Relaxed);
         let was_failing = self.failure_dumped.load(Ordering::Relaxed);
@@ -686,6 +1135,13 @@ impl DeviceState {
         // Reset failure dump flag
         self.failure_dumped.store(false, Ordering::Relaxed);
 
+        // The device may have been power cycled while unreachable
+        let was_offline = *self.online.lock().unwrap() == Some(false);
+        self.power_on_check_pending
+            .store(was_offline, Ordering::Relaxed);
+
+        self.set_online(true).await;
+
         // If device was previously in failed state, log recovery
         if was_failing && last_success > 0 {
             let downtime_ms = now.saturating_sub(last_success);
@@ -771,12 +1227,184 @@ impl DeviceState {
     }
 
     /// Dump timeline on failure
-    pub async fn dump_timeline(&self, failure_reason: &str) {
+    pub async fn dump_timeline(&self, failure_reason: &str, local_key: &str) {
         let log = self.event_log.lock().await;
-        log.dump_timeline(failure_reason);
+        log.dump_timeline(failure_reason, local_key);
+    }
+}
+
+/// Send a set_values command, reporting the outcome to `responder` once the
+/// device acknowledged it
+async fn process_set_values(
+    tuya_device: &Arc<RwLock<TuyaDevice>>,
+    device_state: &Arc<DeviceState>,
+    dps: Value,
+    responder: Option<CommandResponder>,
+    gate: Option<Arc<SyncGate>>,
+) -> Result<()> {
+    let dps_str = serde_json::to_string(&dps).unwrap_or_default();
+    device_state
+        .log_event(DeviceEventType::CommandSent(dps_str))
+        .await;
+
+    let started = Instant::now();
+    let mut reports = device_state.reports.subscribe();
+    let result = timeout(
+        Duration::from_millis(OPERATION_TIMEOUT_MS),
+        send_set_values(tuya_device, &dps, gate.as_deref()),
+    )
+    .await;
+
+    let result = match result {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => {
+            device_state
+                .log_event(DeviceEventType::Error(format!("set_values: {:?}", e)))
+                .await;
+            Err(anyhow!("set_values failed: {:?}", e))
+        }
+        Err(_) => {
+            device_state
+                .log_event(DeviceEventType::Timeout("set_values".to_string()))
+                .await;
+            Err(anyhow!("set_values timeout"))
+        }
+    };
+
+    if let Some(responder) = responder {
+        let error = result.as_ref().err().map(|e| e.to_string());
+
+        // Wait for the device to respond in the background so that the
+        // command queue keeps moving
+        let deadband = device_state.mqtt_client.deadband;
+        tokio::spawn(async move {
+            let error = match error {
+                Some(error) => Some(error),
+                None => {
+                    let remaining = Duration::from_millis(OPERATION_TIMEOUT_MS)
+                        .saturating_sub(started.elapsed());
+                    let mut pending = dps.as_object().cloned().unwrap_or_default();
+                    let wait = acknowledged(&mut reports, &mut pending, deadband);
+                    match timeout(remaining, wait).await {
+                        Ok(true) => None,
+                        Ok(false) => Some("Device disconnected".to_string()),
+                        Err(_) => Some(format!(
+                            "Timed out waiting for device to report DPs {}",
+                            pending.keys().cloned().collect::<Vec<_>>().join(", ")
+                        )),
+                    }
+                }
+            };
+
+            let _ = responder.send(CommandResult {
+                success: error.is_none(),
+                dps,
+                latency_ms: started.elapsed().as_millis() as u64,
+                error,
+            });
+        });
+    }
+
+    result
+}
+
+/// Returns true if `reported` is the `commanded` value of a DP. Bulbs round
+/// brightness, colour temperature and colour, so numbers and the components
+/// of colour DPs may be off by one step, and brightness and saturation (on a
+/// 0-1000 scale) by `deadband` like in `state_changed`.
+fn dp_matches(commanded: &Value, reported: &Value, deadband: f32) -> bool {
+    let tolerance = (deadband * 1000.0).max(1.0) as i64;
+    let hsv = |color: &str| -> Option<Vec<i64>> {
+        if color.len() != 12 {
+            return None;
+        }
+        (0..12)
+            .step_by(4)
+            .map(|i| i64::from_str_radix(color.get(i..i + 4)?, 16).ok())
+            .collect()
+    };
+
+    match (commanded, reported) {
+        (Value::Number(commanded), Value::Number(reported)) => {
+            match (commanded.as_f64(), reported.as_f64()) {
+                (Some(commanded), Some(reported)) => {
+                    (commanded - reported).abs() <= tolerance as f64
+                }
+                _ => false,
+            }
+        }
+        (Value::String(commanded), Value::String(reported)) => {
+            match (hsv(commanded), hsv(reported)) {
+                (Some(commanded), Some(reported)) => {
+                    (commanded[0] - reported[0]).abs() <= 1
+                        && (commanded[1] - reported[1]).abs() <= tolerance
+                        && (commanded[2] - reported[2]).abs() <= tolerance
+                }
+                _ => commanded == reported,
+            }
+        }
+        (commanded, reported) => commanded == reported,
     }
 }
 
+/// Wait until the device acknowledged the DPs in `pending`, removing DPs
+/// from `pending` as they are reported with the commanded value. Devices
+/// don't re-report every DP (e.g. an unchanged mode), so only the DPs that
+/// are reported are compared: the command is acknowledged once one of them
+/// matched and none was last reported with a different value. Returns false
+/// if the device state was dropped first.
+async fn acknowledged(
+    reports: &mut broadcast::Receiver<Map<String, Value>>,
+    pending: &mut Map<String, Value>,
+    deadband: f32,
+) -> bool {
+    let mut matched = false;
+    let mut mismatched = HashSet::new();
+
+    while !pending.is_empty() && (!matched || !mismatched.is_empty()) {
+        match reports.recv().await {
+            Ok(reported) => {
+                for (dp, value) in &reported {
+                    let Some(commanded) = pending.get(dp) else {
+                        continue;
+                    };
+
+                    if dp_matches(commanded, value, deadband) {
+                        pending.remove(dp);
+                        mismatched.remove(dp);
+                        matched = true;
+                    } else {
+                        mismatched.insert(dp.clone());
+                    }
+                }
+            }
+            // Reports that were missed can't be checked, the command may
+            // still be acknowledged by a later one
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return false,
+        }
+    }
+
+    true
+}
+
+/// Encode the command, wait for the other members of a group command, then
+/// send it. The device lock isn't held while waiting, and once released each
+/// member only has to write its pre-encoded frame.
+async fn send_set_values(
+    tuya_device: &RwLock<TuyaDevice>,
+    dps: &Value,
+    gate: Option<&SyncGate>,
+) -> crate::tuyapi::Result<()> {
+    let frame = tuya_device.write().await.encode_set_values(dps.clone())?;
+
+    if let Some(gate) = gate {
+        gate.wait().await;
+    }
+
+    tuya_device.write().await.send_encoded(&frame).await
+}
+
 /// Process commands from the queue with throttling
 async fn process_command(
     tuya_device: &Arc<RwLock<TuyaDevice>>,
@@ -797,9 +1425,11 @@ async fn process_command(
         }
     }
 
-    // Apply throttling
+    // Apply throttling, except to group commands which would otherwise be
+    // staggered again
+    let gated = matches!(command, DeviceCommand::SetValues { gate: Some(_), .. });
     let delay = device_state.throttle_delay();
-    if !delay.is_zero() {
+    if !delay.is_zero() && !gated {
         device_state
             .log_event(DeviceEventType::Throttled {
                 delayed_ms: delay.as_millis() as u64,
@@ -812,18 +1442,28 @@ async fn process_command(
     device_state.mark_command_sent();
 
     // Execute the command
-    let mut tuya = tuya_device.write().await;
-
     match command {
-        DeviceCommand::SetValues(dps) => {
-            let dps_str = serde_json::to_string(&dps).unwrap_or_default();
-            device_state
-                .log_event(DeviceEventType::CommandSent(dps_str))
-                .await;
+        DeviceCommand::SetValues {
+            dps,
+            responder,
+            gate,
+        } => process_set_values(tuya_device, device_state, dps, responder, gate).await,
+        DeviceCommand::Poll => {
+            device_state.log_event(DeviceEventType::PollSent).await;
 
             let result = timeout(
                 Duration::from_millis(OPERATION_TIMEOUT_MS),
-                tuya.set_values(dps),
+                tuya_device
+                    .write()
+                    .await
+                    .get(Payload::Struct(PayloadStruct {
+                        dev_id: device_id.to_string(),
+                        gw_id: Some(device_id.to_string()),
+                        uid: Some(device_id.to_string()),
+                        t: Some("0".to_string()),
+                        dp_id: None,
+                        dps: None,
+                    })),
             )
             .await;
 
@@ -831,31 +1471,34 @@ async fn process_command(
                 Ok(Ok(())) => Ok(()),
                 Ok(Err(e)) => {
                     device_state
-                        .log_event(DeviceEventType::Error(format!("set_values: {:?}", e)))
+                        .log_event(DeviceEventType::Error(format!("poll: {:?}", e)))
                         .await;
-                    Err(anyhow!("set_values failed: {:?}", e))
+                    Err(anyhow!("poll failed: {:?}", e))
                 }
                 Err(_) => {
                     device_state
-                        .log_event(DeviceEventType::Timeout("set_values".to_string()))
+                        .log_event(DeviceEventType::Timeout("poll".to_string()))
                         .await;
-                    Err(anyhow!("set_values timeout"))
+                    Err(anyhow!("poll timeout"))
                 }
             }
         }
-        DeviceCommand::Poll => {
+        DeviceCommand::DpRefresh(dp_ids) => {
             device_state.log_event(DeviceEventType::PollSent).await;
 
             let result = timeout(
                 Duration::from_millis(OPERATION_TIMEOUT_MS),
-                tuya.get(Payload::Struct(PayloadStruct {
-                    dev_id: device_id.to_string(),
-                    gw_id: Some(device_id.to_string()),
-                    uid: Some(device_id.to_string()),
-                    t: Some("0".to_string()),
-                    dp_id: None,
-                    dps: None,
-                })),
+                tuya_device
+                    .write()
+                    .await
+                    .refresh(Payload::Struct(PayloadStruct {
+                        dev_id: device_id.to_string(),
+                        gw_id: None,
+                        uid: Some(device_id.to_string()),
+                        t: Some(Utc::now().timestamp().to_string()),
+                        dp_id: Some(dp_ids),
+                        dps: None,
+                    })),
             )
             .await;
 
@@ -863,15 +1506,15 @@ async fn process_command(
                 Ok(Ok(())) => Ok(()),
                 Ok(Err(e)) => {
                     device_state
-                        .log_event(DeviceEventType::Error(format!("poll: {:?}", e)))
+                        .log_event(DeviceEventType::Error(format!("refresh: {:?}", e)))
                         .await;
-                    Err(anyhow!("poll failed: {:?}", e))
+                    Err(anyhow!("refresh failed: {:?}", e))
                 }
                 Err(_) => {
                     device_state
-                        .log_event(DeviceEventType::Timeout("poll".to_string()))
+                        .log_event(DeviceEventType::Timeout("refresh".to_string()))
                         .await;
-                    Err(anyhow!("poll timeout"))
+                    Err(anyhow!("refresh timeout"))
                 }
             }
         }
@@ -881,7 +1524,7 @@ async fn process_command(
 
             let result = timeout(
                 Duration::from_millis(OPERATION_TIMEOUT_MS),
-                tuya.heartbeat(),
+                tuya_device.write().await.heartbeat(),
             )
             .await;
 
@@ -908,14 +1551,18 @@ async fn process_command(
 }
 
 pub async fn connect_and_poll_with_device(
-    device_config: TuyaDeviceConfig,
+    mut config: watch::Receiver<TuyaDeviceConfig>,
     mqtt_client: MqttClient,
     tuya_device: Arc<RwLock<TuyaDevice>>,
     device_state: Arc<DeviceState>,
+    shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
-    let mqtt_rx_map = mqtt_client.rx_map.clone();
+    let device_config = config.borrow_and_update().clone();
     let id = device_config.id.clone();
 
+    // The config may have been reloaded while disconnected
+    device_state.set_config(&device_config).await;
+
     // Log connection attempt
     device_state
         .log_event(DeviceEventType::ConnectAttempt)
@@ -934,7 +1581,8 @@ pub async fn connect_and_poll_with_device(
 
     // Log successful connection and reset failure dump flag
     device_state.log_event(DeviceEventType::Connected).await;
-    device_state.mark_connected();
+    device_state.mark_connected().await;
+    mqtt_client.states.mark_connected(&device_config);
     info!(
         "Successfully connected to {} (v{})",
         device_config.name, device_config.version
@@ -942,7 +1590,7 @@ pub async fn connect_and_poll_with_device(
 
     // Channel for decoupling MQTT publishing from Tuya receive loop
     // This prevents MQTT slowness from causing Tuya connection timeouts
-    let (mqtt_tx, mut mqtt_publish_rx) = tokio::sync::mpsc::channel::<(String, String)>(16);
+    let (mqtt_tx, mut mqtt_publish_rx) = tokio::sync::mpsc::channel::<StateUpdate>(16);
 
     // Priority command queue using a shared mutex-protected structure
     // This allows us to:
@@ -951,12 +1599,16 @@ pub async fn connect_and_poll_with_device(
     // 3. Prevent queue buildup from delaying user commands
     let command_queue = Arc::new(Mutex::new(PriorityCommandQueue::new()));
     let command_notify = Arc::new(tokio::sync::Notify::new());
+    // Notified whenever the command processor takes a command from the queue
+    let command_taken = Arc::new(tokio::sync::Notify::new());
 
     // Tuya -> MQTT (send to channel, non-blocking)
     let tuya2mqtt = {
-        let device_config = device_config.clone();
-        let mqtt_client = mqtt_client.clone();
+        let config = config.clone();
         let device_state = device_state.clone();
+        let mqtt_tx = mqtt_tx.clone();
+        let command_queue = command_queue.clone();
+        let command_notify = command_notify.clone();
 
         async move {
             // Ignore some garbage data that at least my Tuya lamps send after a
@@ -971,6 +1623,21 @@ pub async fn connect_and_poll_with_device(
                     Ok(Some(result)) => {
                         match &result {
                             Ok(msgs) => {
+                                let is_report = msgs.iter().any(|m| {
+                                    matches!(
+                                        m.command,
+                                        Some(
+                                            CommandType::Control
+                                                | CommandType::ControlNew
+                                                | CommandType::Status
+                                        )
+                                    )
+                                });
+                                if is_report {
+                                    // Nobody may be waiting for an acknowledgement
+                                    let _ = device_state.reports.send(reported_dps(msgs));
+                                }
+
                                 // Log received message summary
                                 let summary = msgs
                                     .iter()
@@ -1003,7 +1670,7 @@ pub async fn connect_and_poll_with_device(
                             .await;
                         warn!(
                             "Receive timeout on {}, connection may be stale",
-                            device_config.name
+                            config.borrow().name
                         );
                         return Err(anyhow!("Receive timeout - connection stale"));
                     }
@@ -1014,6 +1681,7 @@ pub async fn connect_and_poll_with_device(
                     continue;
                 }
 
+                let device_config = config.borrow().clone();
                 let mqtt_device = tuya_to_mqtt(messages, &device_config);
 
                 // TODO: use thiserror if this pattern repeats somewhere else :-)
@@ -1024,16 +1692,33 @@ pub async fn connect_and_poll_with_device(
                 }
 
                 if let Ok(mqtt_device) = mqtt_device {
-                    let json = serde_json::to_string(&mqtt_device)?;
-
-                    let topic = device_config
-                        .topic
-                        .clone()
-                        .unwrap_or_else(|| mqtt_client.topic.replacen('+', &device_config.id, 1));
+                    let check_power_on = device_state
+                        .power_on_check_pending
+                        .swap(false, Ordering::Relaxed);
+
+                    if check_power_on && is_factory_power_on_state(&mqtt_device) {
+                        let restore = match &device_config.power_on {
+                            PowerOnBehavior::AsIs => None,
+                            PowerOnBehavior::Restore => {
+                                device_state.last_commanded.lock().unwrap().clone()
+                            }
+                            PowerOnBehavior::Fixed(state) => Some(*state.clone()),
+                        };
+
+                        if let Some(state) = restore {
+                            info!("{} was power cycled, restoring state", device_config.name);
+                            command_queue.lock().await.push(DeviceCommand::SetValues {
+                                dps: mqtt_to_tuya(state, &device_config),
+                                responder: None,
+                                gate: None,
+                            });
+                            command_notify.notify_one();
+                        }
+                    }
 
                     // Send to channel instead of blocking on MQTT publish
                     // Use try_send to avoid blocking if channel is full (drop old state)
-                    if let Err(e) = mqtt_tx.try_send((topic, json)) {
+                    if let Err(e) = mqtt_tx.try_send(StateUpdate::Reported(mqtt_device)) {
                         debug!("MQTT channel full, dropping message: {:?}", e);
                     }
                 }
@@ -1046,34 +1731,118 @@ pub async fn connect_and_poll_with_device(
 
     // MQTT -> Command Queue (priority queue)
     let mqtt2cmd = {
-        let device_config = device_config.clone();
+        let config = config.clone();
+        let mqtt_client = mqtt_client.clone();
+        let mqtt_tx = mqtt_tx.clone();
+        let device_state = device_state.clone();
         let command_queue = command_queue.clone();
         let command_notify = command_notify.clone();
 
-        let mut mqtt_rx = mqtt_rx_map
-            .get(&device_config.id)
+        let command_taken = command_taken.clone();
+
+        let command_requests = mqtt_client
+            .command_queue(&device_config.id)
             .context(format!(
                 "Could not find configured MQTT device with id {}",
                 device_config.id
-            ))?
-            .clone();
+            ))?;
 
         async move {
             loop {
-                let res = {
-                    mqtt_rx.changed().await?;
-                    let value = &*mqtt_rx.borrow();
-                    value
-                        .clone()
-                        .context("Expected to receive mqtt message from rx channel")?
-                };
+                let request = command_requests.pop().await;
+                let device_config = config.borrow().clone();
+
+                if request.device.refresh {
+                    let refresh = match &device_config.refresh_dps {
+                        Some(dps) => DeviceCommand::DpRefresh(dps.clone()),
+                        None => DeviceCommand::Poll,
+                    };
+                    command_queue.lock().await.push(refresh);
+                    command_notify.notify_one();
+
+                    // Nothing else to do for `{"refresh": true}` or `/get`
+                    if mqtt_to_tuya(request.device.clone(), &device_config)
+                        .as_object()
+                        .is_some_and(|dps| dps.is_empty())
+                    {
+                        continue;
+                    }
+                }
+
+                {
+                    let mut last_commanded = device_state.last_commanded.lock().unwrap();
+                    let previous = last_commanded.take().unwrap_or_default();
+                    *last_commanded = Some(merge_command(&previous, request.device.clone()));
+                }
+
+                if mqtt_client.optimistic {
+                    let update = StateUpdate::Expected(request.device.clone());
+                    if let Err(e) = mqtt_tx.try_send(update) {
+                        debug!("MQTT channel full, dropping optimistic state: {:?}", e);
+                    }
+                }
+
+                let request_id = request.device.request_id.clone();
+                let dps = mqtt_to_tuya(request.device, &device_config);
+
+                // Publish the outcome on the result topic, and as an MQTT 5
+                // response if the request asked for one
+                let (responder, rx) = oneshot::channel::<CommandResult>();
+                let client = mqtt_client.client.clone();
+                let result_topic = device_result_topic(&mqtt_client.topic, &device_config);
+                let id = device_config.id.clone();
+                let reply_to = request.reply_to;
+                let publish_qos = mqtt_client.publish_qos;
+                let gate = request.group.as_ref().map(|group| group.gate.clone());
+                let group_results = request.group.map(|group| group.results);
+
+                tokio::spawn(async move {
+                    let result = rx.await.unwrap_or_else(|_| CommandResult {
+                        success: false,
+                        dps: Value::Null,
+                        latency_ms: 0,
+                        error: Some("Command was dropped".to_string()),
+                    });
+
+                    if let Some(group_results) = group_results {
+                        let _ = group_results.send((id.clone(), result.clone()));
+                    }
+
+                    let mut payload = json!(result);
+                    payload["id"] = json!(id);
+                    payload["request_id"] = json!(request_id);
+                    let payload = payload.to_string();
+
+                    let res = client
+                        .publish_for_device(&id, result_topic, publish_qos, false, payload.clone())
+                        .await;
+                    if let Err(e) = res {
+                        warn!("Error publishing command result for {}: {:?}", id, e);
+                    }
+
+                    if let Some(reply_to) = reply_to {
+                        let res = client.publish_response(&id, &reply_to, payload).await;
+                        if let Err(e) = res {
+                            warn!("Error publishing MQTT response for {}: {:?}", id, e);
+                        }
+                    }
+                });
 
-                let dps = mqtt_to_tuya(res, &device_config);
                 {
                     let mut queue = command_queue.lock().await;
-                    queue.push(DeviceCommand::SetValues(dps));
+                    queue.push(DeviceCommand::SetValues {
+                        dps,
+                        responder: Some(responder),
+                        gate,
+                    });
                 }
                 command_notify.notify_one();
+
+                // Leave further requests in the MQTT command queue, where they
+                // are merged, until this one is being sent
+                while command_queue.lock().await.has_user_commands() {
+                    command_taken.notified().await;
+                }
             }
 
             #[allow(unreachable_code)]
@@ -1129,17 +1898,23 @@ pub async fn connect_and_poll_with_device(
     };
 
     // Command processor - processes commands from priority queue with throttling
+    // Returns once shutdown is requested and queued user commands are done
     let command_processor = {
         let tuya_device = tuya_device.clone();
         let device_state = device_state.clone();
         let command_queue = command_queue.clone();
         let command_notify = command_notify.clone();
+        let command_taken = command_taken.clone();
         let id = id.clone();
+        let mut shutdown = shutdown.clone();
 
         async move {
             loop {
                 // Wait for notification that there's work to do
-                command_notify.notified().await;
+                tokio::select! {
+                    _ = command_notify.notified() => {}
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+                }
 
                 // Process all pending commands in priority order
                 loop {
@@ -1147,6 +1922,7 @@ pub async fn connect_and_poll_with_device(
                         let mut queue = command_queue.lock().await;
                         queue.pop()
                     };
+                    command_taken.notify_one();
 
                     match command {
                         Some(cmd) => {
@@ -1157,8 +1933,21 @@ pub async fn connect_and_poll_with_device(
                 }
             }
 
-            #[allow(unreachable_code)]
-            Err::<(), anyhow::Error>(anyhow!("Command processor exited unexpectedly"))
+            // Shutting down - finish user commands, skip polls and heartbeats
+            loop {
+                let command = {
+                    let mut queue = command_queue.lock().await;
+                    queue.pop_user_command()
+                };
+                command_taken.notify_one();
+
+                match command {
+                    Some(cmd) => process_command(&tuya_device, &device_state, &id, cmd).await?,
+                    None => break,
+                }
+            }
+
+            anyhow::Ok(())
         }
     };
 
@@ -1166,17 +1955,79 @@ pub async fn connect_and_poll_with_device(
     // Decoupled from Tuya receive loop to prevent MQTT slowness from causing timeouts
     let mqtt_publisher = {
         let mqtt_client = mqtt_client.clone();
-        let device_name = device_config.name.clone();
+        let config = config.clone();
 
         async move {
-            while let Some((topic, json)) = mqtt_publish_rx.recv().await {
-                let res = mqtt_client
-                    .client
-                    .publish(topic, QoS::AtLeastOnce, true, json)
-                    .await;
-
-                if let Err(e) = res {
-                    warn!("Error publishing to MQTT for {}: {:?}", device_name, e);
+            // Last published state, when and where it was published
+            let mut last_published: Option<(MqttDevice, Instant, String)> = None;
+
+            while let Some(update) = mqtt_publish_rx.recv().await {
+                let mqtt_device = match update {
+                    StateUpdate::Reported(mqtt_device) => mqtt_device,
+                    // Without a known state there is nothing to merge into,
+                    // wait for the device to report instead
+                    StateUpdate::Expected(command) => match &last_published {
+                        Some((last, _, _)) => merge_command(last, command),
+                        None => continue,
+                    },
+                };
+
+                let device_config = config.borrow().clone();
+                let topic = device_topic(&mqtt_client.topic, &device_config);
+
+                // An optimistic state that turns out to be wrong is corrected
+                // here, as the next report differs from it
+                if let Some((last, published_at, last_topic)) = &last_published {
+                    let refresh_due = mqtt_client
+                        .full_refresh_interval
+                        .is_some_and(|interval| published_at.elapsed() >= interval);
+
+                    if !refresh_due
+                        && *last_topic == topic
+                        && !state_changed(last, &mqtt_device, &device_config, mqtt_client.deadband)
+                    {
+                        continue;
+                    }
+                }
+                last_published = Some((mqtt_device.clone(), Instant::now(), topic.clone()));
+
+                let json = serde_json::to_string(&mqtt_device)?;
+                let mut messages = vec![(topic, json, mqtt_client.retain)];
+
+                // Homie requires retained attribute values
+                if let Some(homie_config) = &mqtt_client.homie {
+                    messages.extend(
+                        homie::state_messages(homie_config, &device_config, &mqtt_device)
+                            .into_iter()
+                            .map(|(topic, payload)| (topic, payload, true)),
+                    );
+                }
+
+                mqtt_client.states.update(&device_config.id, &mqtt_device);
+
+                // Groups containing this device may need to be updated as well
+                for (topic, state) in mqtt_client.groups.update(&device_config.id, &mqtt_device) {
+                    messages.push((topic, serde_json::to_string(&state)?, mqtt_client.retain));
+                }
+
+                for (topic, payload, retain) in messages {
+                    let res = mqtt_client
+                        .client
+                        .publish_for_device(
+                            &device_config.id,
+                            topic,
+                            mqtt_client.publish_qos,
+                            retain,
+                            payload,
+                        )
+                        .await;
+
+                    if let Err(e) = res {
+                        warn!(
+                            "Error publishing to MQTT for {}: {:?}",
+                            device_config.name, e
+                        );
+                    }
                 }
             }
 
@@ -1185,8 +2036,31 @@ pub async fn connect_and_poll_with_device(
         }
     };
 
-    // Loop until any future encounters an error
+    // Reloaded settings that don't require reconnecting
+    let config_updater = {
+        let device_state = device_state.clone();
+        let command_queue = command_queue.clone();
+        let command_notify = command_notify.clone();
+
+        async move {
+            while config.changed().await.is_ok() {
+                let device_config = config.borrow_and_update().clone();
+                device_state.set_config(&device_config).await;
+
+                // Publish the current state with the new settings
+                command_queue.lock().await.push(DeviceCommand::Poll);
+                command_notify.notify_one();
+            }
+
+            // The device is being stopped, shutdown ends the other futures
+            std::future::pending::<()>().await;
+            anyhow::Ok(())
+        }
+    };
+
+    // Loop until any future encounters an error, or until shutdown
     select_all(vec![
+        config_updater.boxed(),
         mqtt2cmd.boxed(),
         tuya2mqtt.boxed(),
         poll_scheduler.boxed(),
@@ -1222,16 +2096,55 @@ fn is_transient_error(error_str: &str) -> bool {
         || error_str.contains("InvalidSessionKey") // ~1/256 chance, retry immediately
 }
 
-pub async fn init_tuya(device_config: TuyaDeviceConfig, mqtt_client: MqttClient) {
+/// Spawn the task for a device, which runs until `shutdown` is set
+///
+/// Changes to `config` are applied without reconnecting, except for the
+/// connection settings (ip, local_key and version) which are fixed.
+pub async fn init_tuya(
+    config: watch::Receiver<TuyaDeviceConfig>,
+    mqtt_client: MqttClient,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let mut reconnect_delay = Duration::from_millis(INITIAL_RECONNECT_DELAY_MS);
+        let device_config = config.borrow().clone();
 
         // Create shared device state for event logging and throttling
-        let device_state = Arc::new(DeviceState::new(
-            device_config.name.clone(),
-            device_config.id.clone(),
-            device_config.version.clone(),
-        ));
+        let device_state = Arc::new(DeviceState::new(&device_config, mqtt_client.clone()));
+
+        // Publish the last known state until the device reports
+        if let Some(state) = mqtt_client.states.get(&device_config.id) {
+            let state = MqttDevice {
+                stale: Some(true),
+                ..state
+            };
+
+            match serde_json::to_string(&state) {
+                Ok(payload) => {
+                    let res = mqtt_client
+                        .client
+                        .publish_for_device(
+                            &device_config.id,
+                            device_topic(&mqtt_client.topic, &device_config),
+                            mqtt_client.publish_qos,
+                            mqtt_client.retain,
+                            payload,
+                        )
+                        .await;
+
+                    if let Err(e) = res {
+                        warn!(
+                            "Error publishing last known state for {}: {:?}",
+                            device_config.name, e
+                        );
+                    }
+                }
+                Err(e) => warn!(
+                    "Could not serialize last known state for {}: {:?}",
+                    device_config.name, e
+                ),
+            }
+        }
 
         // Create shared device handle for explicit cleanup
         let tuya_device = Arc::new(RwLock::new(
@@ -1244,25 +2157,31 @@ pub async fn init_tuya(device_config: TuyaDeviceConfig, mqtt_client: MqttClient)
             .unwrap(),
         ));
 
-        loop {
-            let device_config = device_config.clone();
+        while !*shutdown.borrow() {
             let mqtt_client = mqtt_client.clone();
             let tuya_device = tuya_device.clone();
             let device_state = device_state.clone();
 
-            let name = device_config.name.clone();
+            let name = config.borrow().name.clone();
+            let local_key = device_config.local_key.clone();
             let res = connect_and_poll_with_device(
-                device_config,
+                config.clone(),
                 mqtt_client,
                 tuya_device.clone(),
                 device_state.clone(),
+                shutdown.clone(),
             )
             .await;
 
             match res {
                 Ok(()) => {
-                    // This shouldn't happen as connect_and_poll runs indefinitely
-                    reconnect_delay = Duration::from_millis(INITIAL_RECONNECT_DELAY_MS);
+                    // connect_and_poll only returns Ok on shutdown
+                    device_state.log_event(DeviceEventType::Disconnected).await;
+                    {
+                        let mut device = tuya_device.write().await;
+                        let _ = device.disconnect().await;
+                    }
+                    info!("Disconnected from {} for shutdown", name);
                 }
                 Err(e) => {
                     let error_str = format!("{:?}", e);
@@ -1272,11 +2191,14 @@ pub async fn init_tuya(device_config: TuyaDeviceConfig, mqtt_client: MqttClient)
                         .log_event(DeviceEventType::Error(error_str.clone()))
                         .await;
 
+                    // Let dashboards know the device is unreachable
+                    device_state.mark_disconnected().await;
+
                     // Check if this is a device failure that warrants timeline dump
                     // Only dump if device has been failing for > 1 minute (filters transient issues)
                     if is_device_failure_error(&error_str) && device_state.should_dump_failure() {
                         // Dump the timeline for debugging
-                        device_state.dump_timeline(&error_str).await;
+                        device_state.dump_timeline(&error_str, &local_key).await;
                     }
 
                     // Check if this is a transient error that should reset backoff
@@ -1328,7 +2250,10 @@ pub async fn init_tuya(device_config: TuyaDeviceConfig, mqtt_client: MqttClient)
                     }
 
                     // Wait before reconnecting with exponential backoff
-                    tokio::time::sleep(reconnect_delay).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(reconnect_delay) => {}
+                        _ = shutdown.wait_for(|shutdown| *shutdown) => {}
+                    }
 
                     // Exponential backoff: double the delay, up to maximum
                     // For persistent failures (host unreachable, timeouts, deadlines),
@@ -1338,5 +2263,234 @@ pub async fn init_tuya(device_config: TuyaDeviceConfig, mqtt_client: MqttClient)
                 }
             }
         }
-    });
+
+        // Also replaces the Homie `lost` state of a device that was unreachable
+        device_state.mark_stopped().await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn light(brightness: f32, raw: Value) -> MqttDevice {
+        MqttDevice {
+            id: Some("id".to_string()),
+            name: Some("Light".to_string()),
+            power: Some(true),
+            brightness: Some(brightness),
+            color: None,
+            transition_ms: Some(500.0),
+            sensor_value: None,
+            capabilities: None,
+            raw: Some(raw),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_state_changed_deadband() {
+        let config = TuyaDeviceConfig::default();
+        let old = light(0.5, json!({ "20": true, "22": 500 }));
+
+        assert!(!state_changed(&old, &old.clone(), &config, 0.0));
+        assert!(!state_changed(
+            &old,
+            &light(0.505, json!({ "20": true, "22": 505 })),
+            &config,
+            0.01
+        ));
+        assert!(state_changed(
+            &old,
+            &light(0.52, json!({ "20": true, "22": 520 })),
+            &config,
+            0.01
+        ));
+    }
+
+    #[test]
+    fn test_raw_dps_passthrough() {
+        let config = TuyaDeviceConfig {
+            denied_dps: Some(vec![27]),
+            ..Default::default()
+        };
+        let mut raw = serde_json::Map::new();
+        raw.insert("26".to_string(), json!(60));
+        raw.insert("27".to_string(), json!(true));
+        let command = MqttDevice {
+            power: Some(false),
+            dps: Some(raw),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            mqtt_to_tuya(command, &config),
+            json!({ "26": 60, "20": false })
+        );
+    }
+
+    #[test]
+    fn test_factory_power_on_state() {
+        assert!(is_factory_power_on_state(&light(1.0, Value::Null)));
+        assert!(!is_factory_power_on_state(&light(0.3, Value::Null)));
+        assert!(!is_factory_power_on_state(&MqttDevice {
+            color: Some(DeviceColor::Hs(Hs { h: 120, s: 1.0 })),
+            ..light(1.0, Value::Null)
+        }));
+    }
+
+    #[test]
+    fn test_merge_command() {
+        let state = light(0.5, json!({ "20": true, "22": 500 }));
+        let command = MqttDevice {
+            power: None,
+            brightness: Some(0.8),
+            color: Some(DeviceColor::Ct(Ct { ct: 3000 })),
+            ..light(0.0, Value::Null)
+        };
+
+        let merged = merge_command(&state, command);
+        assert_eq!(merged.power, Some(true));
+        assert_eq!(merged.brightness, Some(0.8));
+        assert_eq!(merged.color, Some(DeviceColor::Ct(Ct { ct: 3000 })));
+        assert_eq!(merged.raw, state.raw);
+    }
+
+    #[test]
+    fn test_state_changed_unmapped_dps() {
+        let config = TuyaDeviceConfig::default();
+        let old = light(0.5, json!({ "20": true, "22": 500, "26": 0 }));
+        let new = light(0.5, json!({ "20": true, "22": 500, "26": 60 }));
+
+        assert!(state_changed(&old, &new, &config, 0.0));
+    }
+
+    #[test]
+    fn test_redact_key() {
+        let key = "0123456789abcdef";
+        let text = format!("bad key {} ({})", key, hex::encode(key));
+        assert_eq!(redact_key(&text, key), "bad key <redacted> (<redacted>)");
+    }
+
+    #[test]
+    fn test_pop_user_command_skips_polls() {
+        let mut queue = PriorityCommandQueue::new();
+        queue.push(DeviceCommand::Poll);
+        queue.push(DeviceCommand::Heartbeat);
+        queue.push(DeviceCommand::SetValues {
+            dps: json!({}),
+            responder: None,
+            gate: None,
+        });
+
+        assert!(matches!(
+            queue.pop_user_command(),
+            Some(DeviceCommand::SetValues { .. })
+        ));
+        assert!(queue.pop_user_command().is_none());
+        assert!(matches!(queue.pop(), Some(DeviceCommand::Poll)));
+    }
+
+    #[tokio::test]
+    async fn test_acknowledged_requires_commanded_dps() {
+        let dps = |value: Value| value.as_object().cloned().unwrap();
+        let (reports_tx, mut reports) = broadcast::channel(REPORTS_CAPACITY);
+        let mut pending = dps(json!({"20": true, "21": "white", "22": 500}));
+
+        // Reports of other DPs or of other values don't acknowledge the command
+        reports_tx.send(dps(json!({"20": true, "22": 10}))).unwrap();
+        reports_tx.send(dps(json!({"23": 100}))).unwrap();
+        let wait = acknowledged(&mut reports, &mut pending, 0.0);
+        assert!(timeout(Duration::from_millis(50), wait).await.is_err());
+        assert_eq!(pending, dps(json!({"21": "white", "22": 500})));
+
+        // The unchanged mode isn't reported again, the brightness is rounded
+        reports_tx.send(dps(json!({"22": 501}))).unwrap();
+        assert!(acknowledged(&mut reports, &mut pending, 0.0).await);
+        assert_eq!(pending, dps(json!({"21": "white"})));
+
+        pending.insert("20".to_string(), json!(false));
+        drop(reports_tx);
+        assert!(!acknowledged(&mut reports, &mut pending, 0.0).await);
+    }
+
+    #[test]
+    fn test_dp_matches() {
+        assert!(dp_matches(&json!(500), &json!(499), 0.0));
+        assert!(!dp_matches(&json!(500), &json!(490), 0.0));
+        assert!(dp_matches(&json!(500), &json!(490), 0.01));
+        assert!(dp_matches(
+            &json!("00f003e801f4"),
+            &json!("00ef03e701f5"),
+            0.0
+        ));
+        assert!(!dp_matches(
+            &json!("00f003e801f4"),
+            &json!("00f003e80100"),
+            0.0
+        ));
+        assert!(!dp_matches(&json!("white"), &json!("colour"), 0.0));
+        assert!(!dp_matches(&json!(true), &json!(false), 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_gated_command_does_not_block_poll() {
+        use tokio::io::AsyncReadExt;
+
+        // Fake device on a loopback address of its own, devices listen on 6668
+        let ip = IpAddr::from_str("127.0.41.1").unwrap();
+        let listener = tokio::net::TcpListener::bind((ip, 6668)).await.unwrap();
+        let mut device = TuyaDevice::new("3.3", "device", Some("0123456789abcdef"), ip).unwrap();
+        let (rx, accepted) = tokio::join!(device.connect(), listener.accept());
+        let _rx = rx.unwrap();
+        let (mut socket, _) = accepted.unwrap();
+        let tuya_device = Arc::new(RwLock::new(device));
+
+        // The other member never gets ready, so this one waits for the deadline
+        let gate = Arc::new(SyncGate::new(2));
+        let gated = tokio::spawn({
+            let tuya_device = tuya_device.clone();
+            async move { send_set_values(&tuya_device, &json!({ "20": true }), Some(&gate)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let poll = async {
+            tuya_device
+                .write()
+                .await
+                .get(Payload::Struct(PayloadStruct {
+                    dev_id: "device".to_string(),
+                    gw_id: None,
+                    uid: None,
+                    t: None,
+                    dp_id: None,
+                    dps: None,
+                }))
+                .await
+        };
+        timeout(Duration::from_millis(500), poll)
+            .await
+            .expect("poll blocked by a waiting group command")
+            .unwrap();
+        assert!(!gated.is_finished());
+
+        gated.await.unwrap().unwrap();
+
+        // Both the poll and the command reached the device
+        let frames = async {
+            let mut received = vec![];
+            let mut buf = [0; 1024];
+            while received
+                .windows(4)
+                .filter(|w| *w == [0, 0, 0x55, 0xaa])
+                .count()
+                < 2
+            {
+                let bytes = socket.read(&mut buf).await.unwrap();
+                assert!(bytes > 0);
+                received.extend_from_slice(&buf[..bytes]);
+            }
+        };
+        timeout(Duration::from_millis(500), frames).await.unwrap();
+    }
 }
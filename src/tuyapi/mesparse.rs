@@ -506,7 +506,7 @@ mod tests {
     fn test_parse_double_messages() {
         let packet =
             hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55000055aa000000000000000a0000000c00000000b051ab030000aa55").unwrap();
-        let expected = vec![
+        let expected = [
             Message {
                 command: Some(CommandType::HeartBeat),
                 payload: Payload::String("".to_string()),
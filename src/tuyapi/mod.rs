@@ -14,11 +14,34 @@ use serde::{Deserialize, Serialize};
 
 use std::convert::TryFrom;
 use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::tuyapi::error::ErrorKind;
 use std::convert::TryInto;
 
 pub type Result<T> = std::result::Result<T, ErrorKind>;
+
+static LOG_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// Log local keys and session keys in full. Only meant for debugging the
+/// protocol, keys are redacted by default.
+pub fn set_log_secrets(enabled: bool) {
+    LOG_SECRETS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn log_secrets() -> bool {
+    LOG_SECRETS.load(Ordering::Relaxed)
+}
+
+/// Key material as it may appear in logs
+pub fn redact(key: &[u8]) -> String {
+    if log_secrets() {
+        hex::encode(key)
+    } else {
+        "<redacted>".to_string()
+    }
+}
+
 /// The Payload enum represents a payload sent to, and recevied from the Tuya devices. It might be
 /// a struct (ser/de from json) or a plain string.
 #[derive(Debug, Clone, PartialEq, Eq)]
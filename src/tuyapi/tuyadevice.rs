@@ -7,6 +7,7 @@
 //! details, create a MessageParser.
 use crate::tuyapi::error::ErrorKind;
 use crate::tuyapi::mesparse::{CommandType, Message, MessageParser, TuyaVersion};
+use crate::tuyapi::redact;
 use crate::tuyapi::{ControlNewPayload, ControlNewPayloadData, Payload, PayloadStruct, Result};
 use aes::cipher::generic_array::GenericArray;
 use aes::cipher::{BlockEncrypt, KeyInit};
@@ -48,21 +49,31 @@ pub struct TuyaConnection {
 
 impl TuyaConnection {
     async fn send(&mut self, mes: &Message) -> Result<()> {
+        let frame = self.encode(mes)?;
+        self.write(&frame).await
+    }
+
+    fn encode(&mut self, mes: &Message) -> Result<Vec<u8>> {
         info!(
-            "Writing message to {} ({}):\n",
+            "Encoding message for {} ({}):\n",
             self.tcp_write_half.peer_addr()?,
             &mes
         );
         let mut mes = (*mes).clone();
-        if matches!(mes.seq_nr, None) {
+        if mes.seq_nr.is_none() {
             mes.seq_nr = Some(self.seq_id.next_id());
         }
-        self.tcp_write_half
-            .write_all(self.mp.encode(&mes, true)?.as_ref())
-            .await?;
-        // info!("Wrote {} bytes", bts);
+        self.mp.encode(&mes, true)
+    }
+
+    async fn write(&mut self, frame: &[u8]) -> Result<()> {
+        info!(
+            "Writing {} bytes to {}",
+            frame.len(),
+            self.tcp_write_half.peer_addr()?
+        );
+        self.tcp_write_half.write_all(frame).await?;
 
-        // self.read().await
         Ok(())
     }
 }
@@ -174,7 +185,10 @@ impl TuyaDevice {
                 .collect();
 
             debug!("nonce_xor: {}", hex::encode(&nonce_xor));
-            debug!("using local_key for crypter: {}", hex::encode(&local_key));
+            debug!(
+                "using local_key for crypter: {}",
+                redact(local_key.as_bytes())
+            );
 
             let local_key_arr = GenericArray::from_slice(local_key.as_bytes());
             let cipher = Aes128::new(local_key_arr);
@@ -183,7 +197,7 @@ impl TuyaDevice {
             let block = GenericArray::from_mut_slice(nonce_xor.as_mut_slice());
             cipher.encrypt_block(block);
 
-            debug!("session key: {}", hex::encode(&block));
+            debug!("session key: {}", redact(block));
 
             // Known v3.4 bug: if first byte of session key is 0x00, device considers it invalid
             // This causes "Error 914: Check device key or version" and connection failures
@@ -272,6 +286,13 @@ impl TuyaDevice {
     }
 
     pub async fn set_values(&mut self, dps: serde_json::Value) -> Result<()> {
+        let frame = self.encode_set_values(dps)?;
+        self.send_encoded(&frame).await
+    }
+
+    /// Encode (and encrypt) a set_values command without sending it, the
+    /// frame is only valid for the current connection
+    pub fn encode_set_values(&mut self, dps: serde_json::Value) -> Result<Vec<u8>> {
         let connection = self.connection.as_mut().ok_or(ErrorKind::NotConnected)?;
         let command = match self.version {
             TuyaVersion::ThreeOne | TuyaVersion::ThreeThree => CommandType::Control,
@@ -301,9 +322,13 @@ impl TuyaDevice {
             }),
         };
         let mes = Message::new(payload, command);
-        connection.send(&mes).await?;
+        connection.encode(&mes)
+    }
 
-        Ok(())
+    /// Send a frame from `encode_set_values`
+    pub async fn send_encoded(&mut self, frame: &[u8]) -> Result<()> {
+        let connection = self.connection.as_mut().ok_or(ErrorKind::NotConnected)?;
+        connection.write(frame).await
     }
 
     pub async fn get(&mut self, tuya_payload: Payload) -> Result<()> {